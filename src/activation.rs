@@ -0,0 +1,303 @@
+use std::{
+    fmt::Display,
+    io::{self, Read},
+    process::{Command, ExitStatus, Stdio},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use camino::Utf8Path;
+
+use crate::remote::Target;
+
+/// Prefix of the transient unit activation runs in. Suffixed to keep concurrent runs apart.
+const UNIT_PREFIX: &str = "nixos-rsbuild-switch-to-configuration";
+
+/// How often a directly spawned activation is checked on, when it has a timeout
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// `switch-to-configuration` exited unsuccessfully. Carries its exit code, so it can be passed on
+/// as ours.
+#[derive(Debug)]
+pub struct ActivationFailed {
+    /// `None` when killed by a signal
+    pub code: Option<i32>,
+}
+
+impl Display for ActivationFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "switch-to-configuration failed with exit code {}", code),
+            None => write!(f, "switch-to-configuration was terminated by a signal"),
+        }
+    }
+}
+
+impl std::error::Error for ActivationFailed {}
+
+impl ActivationFailed {
+    /// Digs an [`ActivationFailed`] back out of the `io::Error` it was passed up in
+    pub fn from_io(err: &io::Error) -> Option<&Self> {
+        err.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+/// Runs `<switch_bin> <action>` as root on the target, in a sanitised environment.
+///
+/// Where the target has `systemd-run`, activation runs in a transient unit of its own, as
+/// nixos-rebuild does: losing the ssh connection or the terminal then no longer kills it half-way
+/// through. Its output is piped back all the same.
+#[derive(Debug)]
+pub struct Activation<'a> {
+    pub switch_bin: &'a Utf8Path,
+    /// switch, boot, test or dry-activate
+    pub action: &'a str,
+    /// Sets `NIXOS_INSTALL_BOOTLOADER=1`, which has the boot loader (re)installed, and not just its
+    /// menu updated.
+    pub install_bootloader: bool,
+    /// Activation is stopped once it runs for longer. `None` waits indefinitely.
+    ///
+    /// Without systemd on a remote target, only the local ssh process can be stopped: the remote
+    /// `switch-to-configuration` may well carry on.
+    pub timeout: Option<Duration>,
+}
+
+impl Activation<'_> {
+    /// # Errors
+    ///
+    /// - [`ActivationFailed`], wrapped as `io::Error`, if switch-to-configuration fails
+    /// - `TimedOut` if it overran the timeout, and had to be stopped without systemd
+    /// - if it could not be run at all
+    pub fn run(&self, target: &Target) -> io::Result<()> {
+        let (mut cmd, in_unit) = self.command(target)?;
+        if in_unit {
+            return check_status(cmd.status()?);
+        }
+        self.spawn_direct(&mut cmd)
+    }
+
+    /// As with [`Activation::run`], capturing what switch-to-configuration reports instead:
+    /// stdout and stderr, interleaved as they were written. Meant for `dry-activate`, so the
+    /// timeout is only enforced when running under systemd.
+    ///
+    /// # Errors
+    ///
+    /// - [`ActivationFailed`], wrapped as `io::Error`, if switch-to-configuration fails
+    /// - if it could not be run at all
+    pub fn output(&self, target: &Target) -> io::Result<String> {
+        let (cmd, _) = self.command(target)?;
+        combined_output(cmd)
+    }
+
+    /// The elevated command activating, and whether it does so in a transient unit
+    fn command(&self, target: &Target) -> io::Result<(Command, bool)> {
+        let locale_arch = std::env::var("LOCALE_ARCHIVE").ok();
+        let env = [
+            locale_arch.as_deref().map(|arch| ("LOCALE_ARCHIVE", arch)),
+            self.install_bootloader
+                .then_some(("NIXOS_INSTALL_BOOTLOADER", "1")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+
+        if has_systemd_run(target) {
+            let unit = unit_name();
+            log::info!("Activating in transient unit {}", unit);
+            let argv = self.systemd_run_argv(&unit, &env);
+            let argv = argv.iter().map(String::as_str).collect::<Vec<_>>();
+            return Ok((target.elevated_command(&argv)?, true));
+        }
+
+        log::warn!("systemd-run is not available; activating as a child process instead");
+        if self.timeout.is_some() && target.remote().is_some() {
+            log::warn!(
+                "The activation timeout only disconnects from the target; it does not stop \
+                 switch-to-configuration there"
+            );
+        }
+        let cmd =
+            target.sanitised_elevated_command(&env, &[self.switch_bin.as_str(), self.action])?;
+        Ok((cmd, false))
+    }
+
+    /// `systemd-run` invocation. Transient units start out with a clean environment, so `env` is
+    /// all the activation gets.
+    fn systemd_run_argv(&self, unit: &str, env: &[(&str, &str)]) -> Vec<String> {
+        let mut argv = vec!["systemd-run".to_string()];
+        for (key, val) in env {
+            argv.extend(["-E".to_string(), format!("{}={}", key, val)]);
+        }
+        argv.extend(
+            [
+                "--collect",
+                "--no-ask-password",
+                "--pipe",
+                "--quiet",
+                "--service-type=exec",
+                "--wait",
+            ]
+            .map(String::from),
+        );
+        argv.push(format!("--unit={}", unit));
+        if let Some(timeout) = self.timeout {
+            argv.push(format!("--property=RuntimeMaxSec={}", timeout.as_secs()));
+        }
+        argv.extend([self.switch_bin.to_string(), self.action.to_string()]);
+        argv
+    }
+
+    fn spawn_direct(&self, cmd: &mut Command) -> io::Result<()> {
+        let mut child = cmd.stdin(Stdio::inherit()).spawn()?;
+        let Some(timeout) = self.timeout else {
+            return check_status(child.wait()?);
+        };
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return check_status(status);
+            }
+            if Instant::now() >= deadline {
+                child.kill()?;
+                child.wait()?;
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "switch-to-configuration did not finish within {}s, and was stopped",
+                        timeout.as_secs()
+                    ),
+                ));
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Whether the target can run a transient unit: it needs `systemd-run`, and to be booted with
+/// systemd, which a container or chroot with systemd installed need not be.
+fn has_systemd_run(target: &Target) -> bool {
+    let succeeds = |argv: &[&str]| {
+        target
+            .command(argv)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    };
+    succeeds(&["test", "-d", "/run/systemd/system"]) && succeeds(&["systemd-run", "--version"])
+}
+
+/// Runs `cmd` with stdout and stderr going to the same pipe, so their lines stay in order
+fn combined_output(mut cmd: Command) -> io::Result<String> {
+    let (mut reader, writer) = io::pipe()?;
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(writer.try_clone()?)
+        .stderr(writer)
+        .spawn()?;
+    // Holds on to the pipe's write end, which would keep the read below from ever finishing
+    drop(cmd);
+    let mut out = Vec::new();
+    reader.read_to_end(&mut out)?;
+    check_status(child.wait()?)?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+/// Unique to this process, and to the moment it activates
+fn unit_name() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos());
+    format!("{}-{}-{}", UNIT_PREFIX, std::process::id(), nanos)
+}
+
+fn check_status(status: ExitStatus) -> io::Result<()> {
+    if status.success() {
+        return Ok(());
+    }
+    Err(io::Error::other(ActivationFailed {
+        code: status.code(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        elevate::Elevate,
+        remote::test_support::{remote_commands, shim_target},
+    };
+
+    #[test]
+    fn systemd_run_argv() {
+        let activation = Activation {
+            switch_bin: Utf8Path::new("/nix/store/abc-nixos/bin/switch-to-configuration"),
+            action: "switch",
+            install_bootloader: true,
+            timeout: Some(Duration::from_secs(600)),
+        };
+        let argv = activation.systemd_run_argv(
+            "unit-1",
+            &[
+                ("LOCALE_ARCHIVE", "/run/locale"),
+                ("NIXOS_INSTALL_BOOTLOADER", "1"),
+            ],
+        );
+        assert_eq!(
+            argv.join(" "),
+            "systemd-run -E LOCALE_ARCHIVE=/run/locale -E NIXOS_INSTALL_BOOTLOADER=1 --collect \
+             --no-ask-password --pipe --quiet --service-type=exec --wait --unit=unit-1 \
+             --property=RuntimeMaxSec=600 /nix/store/abc-nixos/bin/switch-to-configuration switch"
+        );
+    }
+
+    #[test]
+    fn systemd_run_needs_systemd_booted() {
+        let td = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(td.path()).unwrap();
+        let target = shim_target(dir, Elevate::None);
+        let booted = Utf8Path::new("/run/systemd/system").is_dir();
+        assert_eq!(
+            has_systemd_run(&target),
+            booted
+                && Command::new("systemd-run")
+                    .arg("--version")
+                    .output()
+                    .is_ok()
+        );
+        assert_eq!(remote_commands(dir)[0], "test -d /run/systemd/system");
+    }
+
+    #[test]
+    fn output_interleaves_streams() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo out; echo err >&2; echo more out"]);
+        assert_eq!(combined_output(cmd).unwrap(), "out\nerr\nmore out\n");
+
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo oops >&2; exit 4"]);
+        let err = combined_output(cmd).unwrap_err();
+        assert_eq!(ActivationFailed::from_io(&err).unwrap().code, Some(4));
+    }
+
+    #[test]
+    fn direct_exit_code_and_timeout() {
+        let activation = Activation {
+            switch_bin: Utf8Path::new("true"),
+            action: "test",
+            install_bootloader: false,
+            timeout: Some(Duration::from_millis(300)),
+        };
+
+        let err = activation
+            .spawn_direct(Command::new("sh").args(["-c", "exit 3"]))
+            .unwrap_err();
+        assert_eq!(ActivationFailed::from_io(&err).unwrap().code, Some(3));
+
+        let err = activation
+            .spawn_direct(Command::new("sleep").arg("5"))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(ActivationFailed::from_io(&err).is_none());
+    }
+}
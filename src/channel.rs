@@ -0,0 +1,74 @@
+//! `nix-channel` upgrades of root's channels, for `--upgrade` and `--upgrade-all`
+use std::io;
+
+use camino::Utf8Path;
+
+use crate::{remote::Target, utils};
+
+/// Where root's channels are linked, one entry per channel
+pub const ROOT_CHANNELS: &str = "/nix/var/nix/profiles/per-user/root/channels";
+
+/// A channel containing this file is updated by `--upgrade`, alongside `nixos`
+const UPDATE_MARKER: &str = ".update-on-nixos-rebuild";
+
+/// The channels in `channels_dir` that `--upgrade` updates: `nixos`, and those marked with
+/// `.update-on-nixos-rebuild`. Only dirs are channels; the profile's `manifest.nix` is not.
+///
+/// # Errors
+///
+/// If `channels_dir` could not be read
+pub fn channels_to_update(channels_dir: &Utf8Path) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in channels_dir.read_dir_utf8()? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if entry.path().is_dir() && (name == "nixos" || entry.path().join(UPDATE_MARKER).exists()) {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Updates root's channels, as root on this machine. With `all`, a plain `nix-channel --update`
+/// takes every channel root subscribes to, including those never downloaded yet. Either way a
+/// single run creates a single generation of the channels profile.
+///
+/// # Errors
+///
+/// If the channels could not be listed, or `nix-channel --update` fails
+pub fn upgrade(target: &Target, all: bool) -> io::Result<()> {
+    let mut argv = vec!["nix-channel".to_string(), "--update".to_string()];
+    if all {
+        log::info!("Updating all channels");
+    } else {
+        let names = channels_to_update(Utf8Path::new(ROOT_CHANNELS))?;
+        if names.is_empty() {
+            log::warn!("No channels to update in {}", ROOT_CHANNELS);
+            return Ok(());
+        }
+        log::info!("Updating channels {}", names.join(", "));
+        argv.extend(names);
+    }
+    let argv = argv.iter().map(String::as_str).collect::<Vec<_>>();
+    utils::run(&mut target.elevated_command(&argv)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marked_channels() {
+        let td = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(td.path()).unwrap();
+        for name in ["nixos", "home-manager", "nixos-hardware"] {
+            std::fs::create_dir(dir.join(name)).unwrap();
+        }
+        std::fs::write(dir.join("home-manager").join(UPDATE_MARKER), "").unwrap();
+        // The channels profile's own manifest, not a channel
+        std::fs::write(dir.join("manifest.nix"), "[ ]").unwrap();
+
+        assert_eq!(channels_to_update(dir).unwrap(), ["home-manager", "nixos"]);
+    }
+}
@@ -0,0 +1,328 @@
+//! Package-level changes between two closures, in the vein of `nvd`: what a new configuration
+//! would add, remove, upgrade or downgrade relative to the running system.
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    io,
+};
+
+use camino::Utf8Path;
+
+use crate::{list_generations::file_utils::CanonedStorePath, remote::Target, utils};
+
+/// The system currently active on a machine
+pub const CURRENT_SYSTEM: &str = "/run/current-system";
+
+/// A package whose set of versions differs between the closures
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageChange {
+    pub pname: String,
+    /// Versions in the old closure. Empty when added.
+    pub old: Vec<String>,
+    /// Versions in the new closure. Empty when removed.
+    pub new: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClosureDiff {
+    pub upgraded: Vec<PackageChange>,
+    pub downgraded: Vec<PackageChange>,
+    /// Same versions, or versions that don't compare, but different paths. e.g. a package gaining
+    /// an output, or a version being dropped while another is kept.
+    pub changed: Vec<PackageChange>,
+    pub added: Vec<PackageChange>,
+    pub removed: Vec<PackageChange>,
+    /// Closure sizes, in bytes, when known
+    pub sizes: Option<(u64, u64)>,
+}
+
+/// Versions of each package in a closure. Unversioned paths (`etc`, `unit-*.service`) have an
+/// empty version.
+fn package_versions(closure: &[String]) -> BTreeMap<String, BTreeSet<String>> {
+    let mut versions = BTreeMap::<String, BTreeSet<String>>::new();
+    for path in closure {
+        let Ok(store_path) = CanonedStorePath::from_store_path(path) else {
+            log::debug!("skipping non-store path in closure: {}", path);
+            continue;
+        };
+        let (pname, version) = store_path.pname_version();
+        versions
+            .entry(pname.to_string())
+            .or_default()
+            .insert(version.unwrap_or_default().to_string());
+    }
+    versions
+}
+
+/// One component of a version: a run of digits, or a run of anything but digits and separators
+fn next_component(ver: &str) -> (&str, &str) {
+    let ver = ver.trim_start_matches(['.', '-']);
+    let is_digit = ver.starts_with(|c: char| c.is_ascii_digit());
+    let end = ver
+        .find(|c: char| c == '.' || c == '-' || c.is_ascii_digit() != is_digit)
+        .unwrap_or(ver.len());
+    ver.split_at(end)
+}
+
+/// nix's `componentsLT`
+fn component_lt(lhs: &str, rhs: &str) -> bool {
+    let lhs_num = lhs.parse::<u64>().ok();
+    let rhs_num = rhs.parse::<u64>().ok();
+    match (lhs_num, rhs_num) {
+        (Some(lhs), Some(rhs)) => lhs < rhs,
+        (None, Some(_)) if lhs.is_empty() => true,
+        _ if lhs == "pre" && rhs != "pre" => true,
+        _ if rhs == "pre" => false,
+        // `2.3a` < `2.3.1`
+        (_, Some(_)) => true,
+        (Some(_), None) => false,
+        (None, None) => lhs < rhs,
+    }
+}
+
+/// Compares versions the way `nix-env` and `builtins.compareVersions` do
+///
+/// ```
+/// use std::cmp::Ordering;
+/// use nixos_rsbuild::closure_diff::compare_versions;
+/// assert_eq!(compare_versions("1.10", "1.9"), Ordering::Greater);
+/// assert_eq!(compare_versions("2.3pre1", "2.3"), Ordering::Less);
+/// assert_eq!(compare_versions("2.3a", "2.3.1"), Ordering::Less);
+/// assert_eq!(compare_versions("6.6.52", "6.6.52"), Ordering::Equal);
+/// ```
+pub fn compare_versions(lhs: &str, rhs: &str) -> Ordering {
+    let (mut lhs, mut rhs) = (lhs, rhs);
+    while !lhs.is_empty() || !rhs.is_empty() {
+        let (lhs_comp, lhs_rest) = next_component(lhs);
+        let (rhs_comp, rhs_rest) = next_component(rhs);
+        if component_lt(lhs_comp, rhs_comp) {
+            return Ordering::Less;
+        }
+        if component_lt(rhs_comp, lhs_comp) {
+            return Ordering::Greater;
+        }
+        (lhs, rhs) = (lhs_rest, rhs_rest);
+    }
+    Ordering::Equal
+}
+
+fn newest(versions: &BTreeSet<String>) -> Option<&str> {
+    versions
+        .iter()
+        .map(String::as_str)
+        .max_by(|lhs, rhs| compare_versions(lhs, rhs))
+}
+
+impl ClosureDiff {
+    /// Compares two closures, each a list of store paths
+    pub fn between(old: &[String], new: &[String]) -> Self {
+        let old = package_versions(old);
+        let new = package_versions(new);
+        let mut diff = Self::default();
+
+        let pnames = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+        for pname in pnames {
+            let empty = BTreeSet::new();
+            let old_vers = old.get(pname).unwrap_or(&empty);
+            let new_vers = new.get(pname).unwrap_or(&empty);
+            if old_vers == new_vers {
+                continue;
+            }
+
+            let change = PackageChange {
+                pname: pname.clone(),
+                old: old_vers.iter().cloned().collect(),
+                new: new_vers.iter().cloned().collect(),
+            };
+            let group = match (newest(old_vers), newest(new_vers)) {
+                (None, _) => &mut diff.added,
+                (_, None) => &mut diff.removed,
+                (Some(old), Some(new)) => match compare_versions(new, old) {
+                    Ordering::Greater => &mut diff.upgraded,
+                    Ordering::Less => &mut diff.downgraded,
+                    Ordering::Equal => &mut diff.changed,
+                },
+            };
+            group.push(change);
+        }
+        diff
+    }
+
+    /// Diffs the closure of `toplevel` against what's currently running on the target
+    ///
+    /// # Errors
+    ///
+    /// If either closure could not be queried, e.g. when the target isn't running NixOS
+    pub fn against_current(target: &Target, toplevel: &Utf8Path) -> io::Result<Self> {
+        let closure = |path: &str| -> io::Result<Vec<String>> {
+            let out = utils::output(&mut target.command(&["nix-store", "-qR", path]))?;
+            Ok(out.lines().map(String::from).collect())
+        };
+        let mut diff = Self::between(&closure(CURRENT_SYSTEM)?, &closure(toplevel.as_str())?);
+
+        let closure_size = |path: &str| -> io::Result<Option<u64>> {
+            let out =
+                utils::output(&mut target.command(&["nix", "path-info", "--closure-size", path]))?;
+            Ok(parse_closure_size(&out))
+        };
+        diff.sizes = match (
+            closure_size(CURRENT_SYSTEM),
+            closure_size(toplevel.as_str()),
+        ) {
+            (Ok(Some(old)), Ok(Some(new))) => Some((old, new)),
+            (Err(e), _) | (_, Err(e)) => {
+                log::warn!("Could not query closure sizes: {}", e);
+                None
+            }
+            _ => None,
+        };
+        Ok(diff)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.upgraded.is_empty()
+            && self.downgraded.is_empty()
+            && self.changed.is_empty()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+    }
+}
+
+/// `nix path-info --closure-size <path>` prints `<path>\t<size>`
+fn parse_closure_size(out: &str) -> Option<u64> {
+    out.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// `1.2, 1.3`, leaving out the empty version of unversioned paths
+fn versions(vers: &[String]) -> String {
+    vers.iter()
+        .filter(|ver| !ver.is_empty())
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Display for ClosureDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            writeln!(f, "No package changes")?;
+        }
+        for (heading, group) in [
+            ("Upgraded", &self.upgraded),
+            ("Downgraded", &self.downgraded),
+            ("Changed", &self.changed),
+        ] {
+            if group.is_empty() {
+                continue;
+            }
+            writeln!(f, "{} ({}):", heading, group.len())?;
+            for change in group {
+                writeln!(
+                    f,
+                    "  {}: {} -> {}",
+                    change.pname,
+                    versions(&change.old),
+                    versions(&change.new)
+                )?;
+            }
+        }
+        for (heading, group) in [("Added", &self.added), ("Removed", &self.removed)] {
+            if group.is_empty() {
+                continue;
+            }
+            writeln!(f, "{} ({}):", heading, group.len())?;
+            for change in group {
+                // One side is empty
+                let vers = versions(&change.old) + &versions(&change.new);
+                if vers.is_empty() {
+                    writeln!(f, "  {}", change.pname)?;
+                } else {
+                    writeln!(f, "  {}: {}", change.pname, vers)?;
+                }
+            }
+        }
+        if let Some((old, new)) = self.sizes {
+            let (sign, delta) = if new >= old {
+                ("+", new - old)
+            } else {
+                ("-", old - new)
+            };
+            writeln!(
+                f,
+                "Closure size: {} -> {} ({}{})",
+                utils::human_bytes(old),
+                utils::human_bytes(new),
+                sign,
+                utils::human_bytes(delta)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_paths(names: &[&str]) -> Vec<String> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| format!("/nix/store/{:032}-{}", idx, name))
+            .collect()
+    }
+
+    #[test]
+    fn diff_closures() {
+        let old = store_paths(&[
+            "firefox-130.0",
+            "linux-6.6.52",
+            "grafana-11.2.0",
+            "python3-3.12.5",
+            "etc",
+            "unit-grafana.service",
+        ]);
+        let new = store_paths(&[
+            "firefox-131.0.2",
+            "linux-6.6.50",
+            "loki-3.1.0",
+            "python3-3.12.5",
+            "python3-3.11.9",
+            "etc",
+        ]);
+        let diff = ClosureDiff::between(&old, &new);
+
+        fn pnames(group: &[PackageChange]) -> Vec<&str> {
+            group.iter().map(|change| change.pname.as_str()).collect()
+        }
+        assert_eq!(pnames(&diff.upgraded), ["firefox"]);
+        assert_eq!(pnames(&diff.downgraded), ["linux"]);
+        assert_eq!(pnames(&diff.changed), ["python3"]);
+        assert_eq!(pnames(&diff.added), ["loki"]);
+        assert_eq!(pnames(&diff.removed), ["grafana", "unit-grafana.service"]);
+
+        let diff = ClosureDiff {
+            sizes: parse_closure_size("/nix/store/aaa-nixos-system-lab\t1048576000\n").zip(
+                parse_closure_size("/nix/store/bbb-nixos-system-lab\t1038090240\n"),
+            ),
+            ..diff
+        };
+        assert_eq!(
+            diff.to_string(),
+            "Upgraded (1):
+  firefox: 130.0 -> 131.0.2
+Downgraded (1):
+  linux: 6.6.52 -> 6.6.50
+Changed (1):
+  python3: 3.12.5 -> 3.11.9, 3.12.5
+Added (1):
+  loki: 3.1.0
+Removed (2):
+  grafana: 11.2.0
+  unit-grafana.service
+Closure size: 1000.0 MiB -> 990.0 MiB (-10.0 MiB)
+"
+        );
+    }
+}
@@ -1,14 +1,16 @@
 use std::io::{self, ErrorKind};
 
 use camino::Utf8PathBuf;
-use clap::{Args, Parser, Subcommand};
+use clap::{
+    error::ErrorKind as ClapErrorKind, Arg, ArgAction, ArgMatches, Args, Command, FromArgMatches,
+    Parser, Subcommand,
+};
 
-use crate::flake::FlakeRefInput;
+use crate::{elevate::Elevate, flake::FlakeRefInput, profile::Profile, vm::RunVmArgs};
 
 /// Implementations for carrying out the various tasks
 mod handlers;
 mod parsers;
-mod stashed;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -16,10 +18,24 @@ mod stashed;
 pub struct Cli {
     #[command(subcommand)]
     pub command: SubCommand,
+
+    #[clap(
+        long,
+        global = true,
+        env = "NIXOS_RSBUILD_ALLOW_ROOT",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    /// Allow running as root from a terminal. Without a terminal, as with timers and provisioning
+    /// scripts, root is always allowed.
+    ///
+    /// As root, privilege escalation is skipped, and result links are kept under
+    /// `/run/nixos-rsbuild`.
+    pub allow_root: bool,
 }
 
 /// Foobarbaz
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant, reason = "parsed once, at start-up")]
 pub enum SubCommand {
     Builders {
         #[command(subcommand)]
@@ -48,57 +64,143 @@ pub enum BuildSubComms {
     /// Use `--res-dir` to override default directory in which the `result` symlink will be placed
     Build,
     /// Build config only. Show (possibly incomplete) list of changes that its activation
-    DryActivate,
+    ///
+    /// Units that would be stopped, restarted, reloaded and started are grouped, followed by
+    /// what would need a reboot, and the output of the dry activation script.
+    DryActivate {
+        #[clap(long)]
+        /// Outputs the report in json format
+        json: bool,
+    },
     /// No-op, but shows the build/download ops performed by an actual build
-    DryBuild,
+    ///
+    /// Lists the derivations to build, and the paths to fetch with their sizes, followed by the
+    /// total.
+    DryBuild {
+        #[clap(long)]
+        /// Outputs the report in json format
+        json: bool,
+    },
     /// Un-tested. Use at own risk.
     BuildVm,
     /// Un-tested. Use at own risk.
     BuildVmWithBootloader,
+    /// Builds as build-vm does, then launches the VM, waiting for it to shut down
+    RunVm {
+        #[clap(long)]
+        /// Boot the VM through its boot loader, as with build-vm-with-bootloader
+        with_bootloader: bool,
+        #[clap(flatten)]
+        vm: RunVmArgs,
+    },
 }
 
 /// Tools-oriented tasks. See its `-h`/`--help` for more info.
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant, reason = "parsed once, at start-up")]
 pub enum UtilSubCommand {
     /// Output available generations.
     ListGenerations {
         #[clap(long)]
-        /// Outputs generations in json format
+        /// Outputs generations in json format. Fields that couldn't be read are `null`.
         json: bool,
+        #[clap(long, short)]
+        /// Explains, after the table, why any field of a generation couldn't be read
+        verbose: bool,
+        #[clap(long = "profile-name")]
+        #[arg(default_value = "system", value_parser = parsers::profile_name_parse)]
+        /// List the generations of `/nix/var/nix/profiles/system-profiles/$profile-name`
+        profile: Profile,
+    },
+    /// (Re)Installs the boot loader for an existing generation, without rebuilding.
+    ///
+    /// Useful after replacing an ESP, or moving disks.
+    InstallBootloader {
+        /// Generation number to install the boot loader for. Defaults to the current generation.
+        #[arg(name = "GEN")]
+        generation: Option<u32>,
+        #[clap(long = "profile-name")]
+        #[arg(default_value = "system", value_parser = parsers::profile_name_parse)]
+        /// The generation belongs to `/nix/var/nix/profiles/system-profiles/$profile-name`
+        profile: Profile,
+        #[clap(long, value_enum, default_value_t, env = "NIXOS_RSBUILD_ELEVATE")]
+        /// How to obtain root privileges
+        elevate: Elevate,
+        #[clap(long, value_name = "SECS")]
+        /// Stop the activation, should it run for longer
+        activation_timeout: Option<u64>,
+    },
+    /// Opens the configuration in `$VISUAL`/`$EDITOR`, then offers to dry-build it.
+    ///
+    /// Flakes open the file defining the host under `nixosConfigurations`, falling back to
+    /// `flake.nix`. Otherwise, `configuration.nix` (or `--file`) is opened.
+    Edit {
+        #[clap(flatten)]
+        config: ConfigArgs,
+        #[clap(flatten)]
+        eval: FlakeEvalArgs,
     },
-    // /// Opens `configuration.nix` in default editor.
-    // Edit {
-    //     #[clap(flatten)]
-    //     all: AllArgs,
-    // },
+    /// Opens `nix repl` with the host's `config`, `options`, `pkgs` and `lib` in scope
+    Repl {
+        #[clap(flatten)]
+        config: ConfigArgs,
+        #[clap(flatten)]
+        eval: FlakeEvalArgs,
+    },
+}
+
+/// Where the configuration is: a flake output, or a nix file. Subcommands which only need to find
+/// the configuration take just these.
+#[derive(Args, Debug, Clone)]
+pub struct ConfigArgs {
+    #[clap(long, conflicts_with_all(["file", "attr", "no_flake"]))]
+    #[arg(value_parser = parsers::flake_parse, default_value_t = FlakeRefInput::try_default().unwrap())]
+    #[arg(name = "FLAK_REF")]
+    /// Explicitly define the flake path: Typically `.#<hostname>`
+    pub flake: FlakeRefInput,
+
+    #[clap(long, conflicts_with = "flake_eval_args")]
+    /// Build with `nix-build` instead of as a flake.
+    ///
+    /// Without `--file` or `--attr`, builds `<nixpkgs/nixos>`, configured by `$NIXOS_CONFIG`,
+    /// falling back to `/etc/nixos/configuration.nix`. Implied by `--file` and `--attr`.
+    pub no_flake: bool,
+
+    #[clap(long, conflicts_with = "flake_eval_args")]
+    /// Used to select an attrubite other than the default
+    ///
+    /// Builds `<attr>.config.system.build.toplevel` out of `--file`, which defaults to `default.nix`
+    pub attr: Option<String>,
 
-    // /// Opens the configuration using `nix repl`
-    // Repl {
-    //     #[clap(flatten)]
-    //     all: AllArgs,
-    // },
+    #[clap(short = 'I', long = "include", value_name = "PATH")]
+    /// Add a path to the nix search path, as with `nix-build -I`. Can be repeated.
+    pub include: Vec<String>,
+
+    #[clap(long, conflicts_with = "flake_eval_args")]
+    #[arg(value_parser = nix_file_exists)]
+    /// For this build, sets the input file.
+    pub file: Option<Utf8PathBuf>,
 }
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Clone)]
 #[allow(clippy::struct_excessive_bools)]
 pub struct AllArgs {
-    // #[clap(long)]
-    // /// Unimplemented
-    // ///
-    // /// upgrade root-users "nixos" channel, and channels containing `.update-on-nixos-rebuild`
-    // /// marker file in base-dir
-    // pub upgrade: bool,
+    #[clap(long)]
+    /// Upgrade before building.
+    ///
+    /// Non-flake: updates root-users "nixos" channel, and channels containing an
+    /// `.update-on-nixos-rebuild` marker file in base-dir. Flake: updates the lock file of the
+    /// configuration's flake (`nix flake update`).
+    pub upgrade: bool,
 
-    // #[clap(long)]
-    // #[arg(conflicts_with("upgrade"))]
-    // /// Unimplemented
-    // ///
-    // /// --upgrade, but ALL of root-users channels
-    // pub upgrade_all: bool,
+    #[clap(long)]
+    #[arg(conflicts_with("upgrade"))]
+    /// --upgrade, but ALL of root-users channels. Same as --upgrade for flakes.
+    pub upgrade_all: bool,
 
-    // #[clap(long)]
-    // /// (Re)Installs boot loader to device specified by relevant config options.
-    // pub install_bootloader: bool,
+    #[clap(long)]
+    /// (Re)Installs boot loader to device specified by relevant config options.
+    pub install_bootloader: bool,
 
     // #[clap(long, short)]
     // /// Uses currently installed version of Nix.
@@ -107,45 +209,314 @@ pub struct AllArgs {
     // /// This is required when ``NixOS`` modules use features not provided by the currently installed
     // /// version of Nix.
     // pub no_build_nix: bool,
-    #[clap(long, conflicts_with_all(["file"]))] //, "attr", "no_flake"]))]
-    #[arg(value_parser = parsers::flake_parse, default_value_t = FlakeRefInput::try_default().unwrap())]
-    #[arg(name = "FLAK_REF")]
-    /// Explicitly define the flake path: Typically `.#<hostname>`
-    pub flake: FlakeRefInput,
+    #[clap(flatten)]
+    pub config: ConfigArgs,
 
-    // #[clap(long)]
-    // pub no_flake: bool,
+    #[clap(flatten)]
+    pub flake_args: FlakeBuildArgs,
 
-    // #[clap(long)]
-    // /// Used to select an attrubite other than the default
-    // pub attr: Option<String>,
     #[clap(long)]
     /// For this build, sets the input file.
     pub res_dir: Option<Utf8PathBuf>,
 
-    // #[clap(long, short = 's')]
-    // when `--target-host` or `--build-host`, make this one availabel
-    // use_substitutes: bool,
     #[clap(long)]
-    #[arg(value_parser = nix_file_exists)]
-    /// For this build, sets the input file.
-    pub file: Option<Utf8PathBuf>,
-    // #[clap(long = "profile_name")]
-    // #[arg(default_value_t = Utf8PathBuf::from(String::from("/nix/var/nix/profiles/system")), value_parser = parsers::profile_name_parse)]
-    // /// For this build, sets profile directory to `/nix/var/nix/profiles/system-profiles/$profile-name`
-    // pub profile_path: Utf8PathBuf,
+    /// Don't show the package changes relative to `/run/current-system` before switch, boot and
+    /// test.
+    pub no_diff: bool,
 
-    // #[clap(long)]
-    // #[arg(conflicts_with_all(["no_build_nix", "flake", "no_flake", "attr", "file"]))]
-    // pub rollback: bool,
+    #[clap(short = 'L', long)]
+    /// Show the full build logs, rather than just a summary of the build's progress
+    pub print_build_logs: bool,
 
-    // #[clap(long)]
-    // pub build_host: bool,
+    #[clap(long, short = 's')]
+    /// When copying to/from `--target-host` or `--build-host`, have the destination fetch what it
+    /// can from its substituters, instead of copying everything over ssh.
+    pub use_substitutes: bool,
 
+    #[clap(long = "profile-name")]
+    #[arg(default_value = "system", value_parser = parsers::profile_name_parse)]
+    /// For this build, sets profile directory to `/nix/var/nix/profiles/system-profiles/$profile-name`
+    ///
+    /// `system` refers to the default `/nix/var/nix/profiles/system`
+    pub profile: Profile,
+
+    #[clap(long)]
+    /// Activates the named specialisation of the configuration. Only valid for switch and test.
+    pub specialisation: Option<String>,
+
+    #[clap(long)]
+    #[arg(conflicts_with_all(["FLAK_REF", "file", "no_flake", "attr"]))] //, "no_build_nix"]))]
+    /// Activates the previous generation instead of building. Only valid for switch, boot, test and
+    /// build.
+    ///
+    /// switch/boot roll the profile back to its previous generation. test/build only use the
+    /// previous generation, leaving the profile as-is.
+    pub rollback: bool,
+    #[clap(long, value_name = "[USER@]HOST")]
+    /// Deploy to a remote machine, over ssh. The configuration is still built locally.
+    ///
+    /// Escalates on the remote machine as per `--elevate`. `$NIX_SSHOPTS` is passed on to ssh.
+    pub target_host: Option<String>,
+
+    #[clap(long, value_enum, default_value_t, env = "NIXOS_RSBUILD_ELEVATE")]
+    /// How to obtain root privileges, when registering and activating the configuration.
+    ///
+    /// Applies to `--target-host` when set. The sanitised environment of the activation is set up
+    /// after escalating, so all methods behave the same.
+    pub elevate: Elevate,
+
+    #[clap(long, value_name = "SECS")]
+    /// Stop switch-to-configuration, should it run for longer.
+    ///
+    /// Activation runs in a transient `systemd-run` unit where available, so it survives losing
+    /// the terminal or ssh connection; the timeout is then enforced by systemd. Without it, a
+    /// `--target-host` is merely disconnected from once the timeout passes.
+    pub activation_timeout: Option<u64>,
+
+    #[clap(long, value_name = "[USER@]HOST", conflicts_with = "rollback")]
+    /// Build on a remote machine, over ssh.
+    ///
+    /// The configuration is evaluated locally, and the derivation copied to, and realised on, the
+    /// build host. The result is then copied to the target: this machine, or `--target-host`.
+    pub build_host: Option<String>,
     // #[clap(long)]
     // pub fast: bool,
 }
 
+/// Lock-file and evaluation flags, forwarded to `nix build`. Only applicable to flake builds.
+#[derive(Args, Debug, Clone)]
+// The group's members are listed explicitly: the derive leaves them out once a field is flattened
+#[group(id = "flake_build_args", multiple = true, args = [
+    "recreate_lock_file",
+    "no_update_lock_file",
+    "no_write_lock_file",
+    "no_registries",
+    "commit_lock_file",
+    "update_input",
+], conflicts_with_all = ["no_flake", "attr", "file"])]
+#[allow(clippy::struct_excessive_bools)]
+pub struct FlakeBuildArgs {
+    #[clap(long)]
+    /// Recreate the flake's lock file from scratch
+    pub recreate_lock_file: bool,
+    #[clap(long)]
+    /// Do not allow any updates to the flake's lock file
+    pub no_update_lock_file: bool,
+    #[clap(long)]
+    /// Do not write the flake's newly generated lock file
+    pub no_write_lock_file: bool,
+    #[clap(long)]
+    /// Don't allow lookups in the flake registries
+    pub no_registries: bool,
+    #[clap(long)]
+    /// Commit changes to the flake's lock file
+    pub commit_lock_file: bool,
+    #[clap(long, value_name = "INPUT_PATH")]
+    /// Update a specific flake input (ignoring its previous entry in the lock file). Can be repeated.
+    pub update_input: Vec<String>,
+    #[clap(flatten)]
+    pub eval: FlakeEvalArgs,
+}
+
+/// Flags changing what a flake evaluates to, for anything evaluating one. Only applicable to
+/// flakes.
+#[derive(Args, Debug, Clone, Default)]
+#[group(id = "flake_eval_args", multiple = true, args = [OverrideInputs::ID, "impure"])]
+pub struct FlakeEvalArgs {
+    #[clap(flatten)]
+    override_input: OverrideInputs,
+    #[clap(long)]
+    /// Allow access to mutable paths and repositories
+    pub impure: bool,
+}
+
+/// `--override-input <input_path> <flake_url>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OverrideInput {
+    pub input_path: String,
+    pub flake_url: String,
+}
+
+/// Every `--override-input`, paired up per occurrence. The derive can't group an option's values
+/// by occurrence, hence the manual impls.
+#[derive(Debug, Clone, Default)]
+struct OverrideInputs(Vec<OverrideInput>);
+
+impl OverrideInputs {
+    const ID: &'static str = "override_input";
+}
+
+impl FromArgMatches for OverrideInputs {
+    fn from_arg_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        let Some(occurrences) = matches.get_occurrences::<String>(Self::ID) else {
+            return Ok(Self::default());
+        };
+        occurrences
+            .map(|mut vals| match (vals.next(), vals.next()) {
+                (Some(input_path), Some(flake_url)) => Ok(OverrideInput {
+                    input_path: input_path.clone(),
+                    flake_url: flake_url.clone(),
+                }),
+                _ => Err(clap::Error::new(ClapErrorKind::WrongNumberOfValues)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    fn update_from_arg_matches(&mut self, matches: &ArgMatches) -> Result<(), clap::Error> {
+        *self = Self::from_arg_matches(matches)?;
+        Ok(())
+    }
+}
+
+impl Args for OverrideInputs {
+    fn augment_args(cmd: Command) -> Command {
+        cmd.arg(
+            Arg::new(Self::ID)
+                .long("override-input")
+                .num_args(2)
+                .action(ArgAction::Append)
+                .value_names(["INPUT_PATH", "FLAKE_URL"])
+                .help("Override a specific flake input (e.g. `dwarffs/nixpkgs`). Can be repeated.")
+                .long_help(
+                    "Override a specific flake input (e.g. `dwarffs/nixpkgs`). Can be repeated.\n\n\
+                     e.g. `--override-input nixpkgs ~/src/nixpkgs`",
+                ),
+        )
+    }
+
+    fn augment_args_for_update(cmd: Command) -> Command {
+        Self::augment_args(cmd)
+    }
+}
+
+impl FlakeEvalArgs {
+    /// Each `--override-input` occurrence, which clap groups into its two values
+    pub fn override_inputs(&self) -> impl Iterator<Item = OverrideInput> + '_ {
+        self.override_input.0.iter().cloned()
+    }
+
+    /// The flags as they are passed to `nix`
+    pub fn nix_flags(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        if self.impure {
+            flags.push("--impure".to_string());
+        }
+        for input in self.override_inputs() {
+            flags.extend([
+                "--override-input".to_string(),
+                input.input_path,
+                input.flake_url,
+            ]);
+        }
+        flags
+    }
+}
+
+impl FlakeBuildArgs {
+    /// The flags as they are passed to `nix build`
+    pub fn nix_flags(&self) -> Vec<String> {
+        let switches = [
+            (self.recreate_lock_file, "--recreate-lock-file"),
+            (self.no_update_lock_file, "--no-update-lock-file"),
+            (self.no_write_lock_file, "--no-write-lock-file"),
+            (self.no_registries, "--no-registries"),
+            (self.commit_lock_file, "--commit-lock-file"),
+        ];
+        let mut flags = switches
+            .into_iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, flag)| flag.to_string())
+            .collect::<Vec<_>>();
+        for input in &self.update_input {
+            flags.extend(["--update-input".to_string(), input.clone()]);
+        }
+        flags.extend(self.eval.nix_flags());
+        flags
+    }
+}
+
+impl ConfigArgs {
+    /// Flake mode is the default, unless opted out of, explicitly or by selecting a file/attribute.
+    pub fn is_flake_build(&self) -> bool {
+        !(self.no_flake || self.file.is_some() || self.attr.is_some())
+    }
+
+    /// `-I` for each `--include`
+    pub fn include_flags(&self) -> Vec<String> {
+        self.include
+            .iter()
+            .flat_map(|path| ["-I".to_string(), path.clone()])
+            .collect()
+    }
+
+    /// Flags for evaluating the configuration, with `eval` only applying to flakes
+    pub fn nix_eval_flags(&self, eval: &FlakeEvalArgs) -> Vec<String> {
+        let mut flags = self.include_flags();
+        if self.is_flake_build() {
+            flags.extend(eval.nix_flags());
+        }
+        flags
+    }
+}
+
+impl AllArgs {
+    /// The defaults, as parsed from an empty command line, for everything but the configuration
+    /// and how it evaluates. For building what a utility located.
+    ///
+    /// # Errors
+    ///
+    /// If the defaults can't be parsed, e.g. for an invalid `$NIXOS_RSBUILD_ELEVATE`
+    pub fn with_config(config: ConfigArgs, eval: FlakeEvalArgs) -> io::Result<Self> {
+        let matches = Self::augment_args(Command::new("nixos-rsbuild"))
+            .try_get_matches_from(["nixos-rsbuild"])
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        let mut args = Self::from_arg_matches(&matches)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        args.config = config;
+        args.flake_args.eval = eval;
+        Ok(args)
+    }
+
+    /// Flake mode is the default, unless opted out of, explicitly or by selecting a file/attribute.
+    pub fn is_flake_build(&self) -> bool {
+        self.config.is_flake_build()
+    }
+
+    /// Flags passed through to `nix build`/`nix-build`, as appropriate for the mode.
+    pub fn nix_build_flags(&self) -> Vec<String> {
+        let mut flags = self.config.include_flags();
+        if self.is_flake_build() {
+            flags.extend(self.flake_args.nix_flags());
+        }
+        flags
+    }
+}
+
+impl BuildSubComms {
+    /// The attribute under `config.system.build` that this task builds.
+    pub fn build_output(&self) -> &'static str {
+        match self {
+            Self::Switch
+            | Self::Boot
+            | Self::Test
+            | Self::DryBuild { .. }
+            | Self::Build
+            | Self::DryActivate { .. } => "toplevel",
+            Self::BuildVm
+            | Self::RunVm {
+                with_bootloader: false,
+                ..
+            } => "vm",
+            Self::BuildVmWithBootloader
+            | Self::RunVm {
+                with_bootloader: true,
+                ..
+            } => "vmWithBootLoader",
+        }
+    }
+}
+
 fn nix_file_exists(path: &str) -> io::Result<Utf8PathBuf> {
     let path = Utf8PathBuf::from(path);
     if !path.exists() {
@@ -155,16 +526,150 @@ fn nix_file_exists(path: &str) -> io::Result<Utf8PathBuf> {
         ));
     }
     if !path.is_file() {
-        return Err(io::Error::new(
-            ErrorKind::Other,
-            format!("Not a file: {}", path.as_str()),
-        ));
+        return Err(io::Error::other(format!("Not a file: {}", path.as_str())));
     }
     if !matches!(path.extension(), Some("nix")) {
-        return Err(io::Error::new(
-            ErrorKind::Other,
-            "Requires '.nix' extension".to_string(),
-        ));
+        return Err(io::Error::other("Requires '.nix' extension".to_string()));
     }
     Ok(path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all_args(args: &[&str]) -> AllArgs {
+        let cli = Cli::try_parse_from(
+            ["nixos-rsbuild", "builders"]
+                .iter()
+                .chain(args)
+                .chain(&["build"]),
+        )
+        .unwrap();
+        let SubCommand::Builders { arg, .. } = cli.command else {
+            unreachable!()
+        };
+        arg
+    }
+
+    #[test]
+    fn flake_build_flags() {
+        let args = parse_all_args(&[
+            "--override-input",
+            "nixpkgs",
+            "/home/me/src/nixpkgs",
+            "--impure",
+            "--update-input",
+            "home-manager",
+            "--override-input",
+            "hardware",
+            "github:NixOS/nixos-hardware",
+        ]);
+        assert_eq!(
+            args.flake_args.eval.override_inputs().collect::<Vec<_>>(),
+            vec![
+                OverrideInput {
+                    input_path: "nixpkgs".into(),
+                    flake_url: "/home/me/src/nixpkgs".into()
+                },
+                OverrideInput {
+                    input_path: "hardware".into(),
+                    flake_url: "github:NixOS/nixos-hardware".into()
+                },
+            ]
+        );
+        assert_eq!(
+            args.nix_build_flags(),
+            [
+                "--update-input",
+                "home-manager",
+                "--impure",
+                "--override-input",
+                "nixpkgs",
+                "/home/me/src/nixpkgs",
+                "--override-input",
+                "hardware",
+                "github:NixOS/nixos-hardware",
+            ]
+        );
+    }
+
+    #[test]
+    fn override_input_takes_pairs() {
+        for args in [
+            &["--override-input", "nixpkgs", "--impure"][..],
+            &["--override-input", "nixpkgs", "/src/nixpkgs", "--no-flake"],
+        ] {
+            let res = Cli::try_parse_from(
+                ["nixos-rsbuild", "builders"]
+                    .iter()
+                    .chain(args)
+                    .chain(&["build"]),
+            );
+            assert!(res.is_err());
+        }
+    }
+
+    #[test]
+    fn flake_build_flags_conflict_with_non_flake() {
+        for flake_arg in ["--impure", "--commit-lock-file"] {
+            for non_flake in [&["--no-flake"][..], &["--attr", "lab"]] {
+                let res = Cli::try_parse_from(
+                    ["nixos-rsbuild", "builders", flake_arg]
+                        .iter()
+                        .chain(non_flake)
+                        .chain(&["build"]),
+                );
+                assert!(res.is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn edit_takes_only_config_args() {
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(["nixos-rsbuild", "util", "edit"].iter().chain(args))
+        };
+        assert!(parse(&["--no-flake", "-I", "nixpkgs=/src/nixpkgs"]).is_ok());
+        for deploy in [
+            &["--target-host", "lab"][..],
+            &["--upgrade"],
+            &["--rollback"],
+        ] {
+            assert!(parse(deploy).is_err());
+        }
+    }
+
+    #[test]
+    fn repl_takes_eval_flags_only() {
+        let cli = Cli::try_parse_from([
+            "nixos-rsbuild",
+            "util",
+            "repl",
+            "--impure",
+            "--override-input",
+            "nixpkgs",
+            "/src/nixpkgs",
+        ])
+        .unwrap();
+        let SubCommand::Util {
+            task: UtilSubCommand::Repl { config, eval },
+        } = cli.command
+        else {
+            unreachable!()
+        };
+        assert_eq!(
+            config.nix_eval_flags(&eval),
+            ["--impure", "--override-input", "nixpkgs", "/src/nixpkgs"]
+        );
+
+        for args in [
+            &["--update-input", "nixpkgs"][..],
+            &["--commit-lock-file"],
+            &["--no-flake", "--impure"],
+        ] {
+            let res = Cli::try_parse_from(["nixos-rsbuild", "util", "repl"].iter().chain(args));
+            assert!(res.is_err());
+        }
+    }
+}
@@ -1,63 +1,524 @@
-use std::io;
+use std::{
+    io::{self, IsTerminal, Write},
+    process::Command,
+    time::Duration,
+};
 
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use tempdir::TempDir;
 
 use super::AllArgs;
+use crate::{
+    activation::Activation,
+    channel,
+    closure_diff::ClosureDiff,
+    dry_activate::DryActivateReport,
+    dry_build::{self, DryBuildReport},
+    flake::FlakeRef,
+    list_generations::GenDescTable,
+    nix_file::NixFileRef,
+    remote::{NixCopy, SshHost, Target},
+    utils, vm,
+};
+
+impl super::UtilSubCommand {
+    /// Carries out the tool-oriented tasks. None of these build a configuration.
+    pub fn run_util(&self) -> io::Result<()> {
+        match self {
+            Self::ListGenerations {
+                json,
+                verbose,
+                profile,
+            } => {
+                let table = GenDescTable::of_profile(profile)?;
+                if *json {
+                    println!("{}", serde_json::to_string_pretty(&table)?);
+                } else if *verbose {
+                    print!("{:#}", table);
+                } else {
+                    print!("{}", table);
+                }
+                Ok(())
+            }
+            Self::InstallBootloader {
+                generation,
+                profile,
+                elevate,
+                activation_timeout,
+            } => {
+                let gen_link = match generation {
+                    Some(num) => profile.generation_link((*num).into()),
+                    None => profile.path().to_path_buf(),
+                };
+                let toplevel = gen_link.canonicalize_utf8().map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("Could not resolve generation {}: {}", gen_link, e),
+                    )
+                })?;
+                log::info!("Installing boot loader for {}", toplevel);
+                Activation {
+                    switch_bin: &toplevel.join("bin/switch-to-configuration"),
+                    action: "boot",
+                    install_bootloader: true,
+                    timeout: activation_timeout.map(Duration::from_secs),
+                }
+                .run(&Target::local(*elevate))
+            }
+            Self::Edit { config, eval } => {
+                let file = if config.is_flake_build() {
+                    config.flake.host_definition(&eval.nix_flags())?
+                } else {
+                    NixFileRef::config_file(config.file.as_deref(), &config.include)
+                };
+                open_in_editor(&file)?;
+
+                if io::stdin().is_terminal() && confirm("Run dry-build now?")? {
+                    let all = AllArgs::with_config(config.clone(), eval.clone())?;
+                    super::BuildSubComms::DryBuild { json: false }.run_build(all)?;
+                }
+                Ok(())
+            }
+            Self::Repl { config, eval } => {
+                let repl = if config.is_flake_build() {
+                    config.flake.host_repl()?
+                } else {
+                    NixFileRef::host_repl(
+                        config.file.as_deref(),
+                        config.attr.as_deref(),
+                        &config.include,
+                    )?
+                };
+                repl.run(&config.nix_eval_flags(eval))
+            }
+        }
+    }
+}
+
+/// Opens `file` in `$VISUAL`, or `$EDITOR`, falling back to `nano`. The variable may carry
+/// arguments, e.g. `code --wait`.
+fn open_in_editor(file: &Utf8Path) -> io::Result<()> {
+    let editor = std::env::var("VISUAL")
+        .ok()
+        .filter(|var| !var.trim().is_empty())
+        .or_else(|| std::env::var("EDITOR").ok())
+        .filter(|var| !var.trim().is_empty())
+        .unwrap_or_else(|| "nano".to_string());
+    let mut argv = editor.split_whitespace();
+    let program = argv.next().expect("editor is non-empty");
+
+    log::info!("Opening {} in {}", file, program);
+    let mut cmd = Command::new(program);
+    cmd.args(argv).arg(file);
+    utils::run(&mut cmd)
+}
+
+/// Asks a yes/no question on the terminal. Anything but `y`/`yes` is a no.
+fn confirm(question: &str) -> io::Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
 
 impl super::BuildSubComms {
     /// Builds a config, capturing a sym-link. Follows up with a call to `switch-to-configuration`
     /// as appropriate.
     pub fn run_build(&self, args: AllArgs) -> io::Result<()> {
-        log::trace!("Constructing configuration: {:?}", args);
-        let (res_dir, use_td) = self.build_configuration(args)?;
+        if args.specialisation.is_some() && !matches!(self, Self::Switch | Self::Test) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "--specialisation can only be used with switch and test. Got: {}",
+                    self
+                ),
+            ));
+        }
+        let target = Target::init(args.target_host.as_deref(), args.elevate)?;
+        if args.rollback {
+            return self.run_rollback(&args, &target);
+        }
+        if let Self::DryBuild { json } = self {
+            return self.run_dry_build(&args, *json);
+        }
 
-        // Execute switch-to-configuration provided by the configuration build.
-        // This is where the switch/boot/test/dry-activate component gets carried out
-        if matches!(
-            self,
-            Self::Switch | Self::Boot | Self::Test | Self::DryActivate
-        ) {
-            let switch_bin = res_dir.join("result/bin/switch-to-configuration");
-            let out_link = std::fs::canonicalize(switch_bin).unwrap();
+        log::trace!("Constructing configuration: {:?}", args);
+        let use_td = args.res_dir.is_none();
+        let res_dir = match &args.res_dir {
+            Some(dir) => dir.clone(),
+            None => Utf8PathBuf::from_path_buf(
+                TempDir::new_in(utils::res_dir_base()?, "nixrsbuild-")?.into_path(),
+            )
+            .unwrap(),
+        };
+        log::trace!("Result link directory: {}", res_dir);
 
-            let local_arch = std::env::var_os("LOCALE_ARCHIVE").unwrap_or_default();
-            let task_str = self.to_string();
-            let _ = cmd_lib::spawn!(
-                sudo nu -c "env -i LOCALE_ARCHIVE=$local_arch $out_link $task_str"
-            )?
-            .wait();
-        }
+        let res =
+            self.build_configuration(&args, &res_dir, &target)
+                .and_then(|toplevel| match self {
+                    Self::RunVm { vm, .. } => vm::run_vm(&res_dir, vm),
+                    _ => self.deploy_configuration(&toplevel, &args, &target),
+                });
 
         // Sanity-check that we are actually cleaning up a tempdir, and not nuking something that
         // shouldn't be. This could justifyably be removed, as the OS GCs the tempdir anyway.
         if use_td {
             log::trace!("Cleaning up tempdir used to link to nix-store: {}", res_dir);
-            let sys_td = std::env::temp_dir();
-            assert!(std::fs::exists(&sys_td).unwrap());
-            assert!(res_dir.starts_with(sys_td));
+            let base = utils::res_dir_base()?;
+            assert!(std::fs::exists(&base).unwrap());
+            assert!(res_dir.starts_with(base));
             assert!(
                 res_dir.file_name().unwrap().starts_with("nixrsbuild-"),
                 "{}",
                 res_dir.file_name().unwrap()
             );
-            let _ = std::fs::remove_dir_all(res_dir);
+            let _ = std::fs::remove_dir_all(&res_dir);
         }
 
+        res
+    }
+
+    /// Reports what building would take, without building: derivations to build, and paths to
+    /// fetch with their sizes.
+    fn run_dry_build(&self, args: &AllArgs, json: bool) -> io::Result<()> {
+        let build_ref = BuildRef::init(self, args)?;
+        build_ref.upgrade(args)?;
+        let dry_run = build_ref.dry_run(&args.nix_build_flags())?;
+        let mut report = DryBuildReport::parse(&dry_run);
+        if !report.fetch.is_empty() {
+            match dry_build::substituters() {
+                Ok(substituters) => report.fill_sizes(&substituters),
+                Err(e) => log::warn!("Could not look up substituters, for path sizes: {}", e),
+            }
+        }
+
+        if json {
+            let out = serde_json::to_string_pretty(&report)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            println!("{}", out);
+        } else {
+            println!("{}", report);
+        }
         Ok(())
     }
 
-    /// Builds the configuration, and returns the link to the nix store repo. The `bool` tag
-    /// indicates if the link is placed in a temp-dir.
-    fn build_configuration(&self, args: AllArgs) -> io::Result<(Utf8PathBuf, bool)> {
-        let use_td = args.res_dir.is_none();
-        let full_flake = args.flake.init_flake_ref(self)?;
-        let res_dir = args.res_dir.unwrap_or(
-            Utf8PathBuf::from_path_buf(TempDir::new("nixrsbuild-")?.into_path()).unwrap(),
+    /// Skips evaluation entirely, and works with the previous generation of the profile instead.
+    ///
+    /// - switch/boot: `nix-env --rollback`, then activates what the profile now points to
+    /// - test: activates the previous generation, leaving the profile untouched
+    /// - build: links `result` to the previous generation, when it is on this machine. Otherwise,
+    ///   prints its path
+    fn run_rollback(&self, args: &AllArgs, target: &Target) -> io::Result<()> {
+        let profile = &args.profile;
+        match self {
+            Self::Switch | Self::Boot => {
+                profile.rollback(target)?;
+                let toplevel = target.canonicalize(profile.path())?;
+                self.switch_to_configuration(target, &toplevel, args)
+            }
+            Self::Test | Self::Build => {
+                let prev_gen = profile.previous_generation(target)?;
+                let gen_link = profile.generation_link(prev_gen);
+                log::info!("Using previous generation: {}", gen_link);
+                if matches!(self, Self::Test) {
+                    let toplevel = target.canonicalize(&gen_link)?;
+                    return self.switch_to_configuration(target, &toplevel, args);
+                }
+                if let Some(host) = target.remote() {
+                    log::warn!(
+                        "No result link is created for a generation on {}, it is at:",
+                        host.host()
+                    );
+                    println!("{}", target.canonicalize(&gen_link)?);
+                    return Ok(());
+                }
+
+                let res_link = args
+                    .res_dir
+                    .as_deref()
+                    .unwrap_or(Utf8Path::new("."))
+                    .join("result");
+                std::os::unix::fs::symlink(&gen_link, &res_link).map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("Could not link {} to {}: {}", res_link, gen_link, e),
+                    )
+                })
+            }
+            Self::DryActivate { .. }
+            | Self::DryBuild { .. }
+            | Self::BuildVm
+            | Self::BuildVmWithBootloader
+            | Self::RunVm { .. } => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "--rollback is only supported by switch, boot, test and build. Got: {}",
+                    self
+                ),
+            )),
+        }
+    }
+
+    /// Registers the new generation in the profile (switch/boot), then activates it as
+    /// appropriate.
+    fn deploy_configuration(
+        &self,
+        toplevel: &Utf8Path,
+        args: &AllArgs,
+        target: &Target,
+    ) -> io::Result<()> {
+        if !self.activates() {
+            return Ok(());
+        }
+
+        if matches!(self, Self::Switch | Self::Boot | Self::Test) && !args.no_diff {
+            match ClosureDiff::against_current(target, toplevel) {
+                Ok(diff) => print!("{}", diff),
+                Err(e) => log::warn!("Could not diff against the current system: {}", e),
+            }
+        }
+
+        // The boot-menu is generated from the profiles generations, so it must be registered
+        // before activation.
+        if matches!(self, Self::Switch | Self::Boot) {
+            args.profile.set_generation(target, toplevel)?;
+        }
+
+        self.switch_to_configuration(target, toplevel, args)
+    }
+
+    /// Execute switch-to-configuration provided by the configuration build, or by the selected
+    /// specialisation. This is where the switch/boot/test/dry-activate component gets carried out
+    fn switch_to_configuration(
+        &self,
+        target: &Target,
+        toplevel: &Utf8Path,
+        args: &AllArgs,
+    ) -> io::Result<()> {
+        let out_link = switch_to_config_bin(target, toplevel, args.specialisation.as_deref())?;
+        let activation = Activation {
+            switch_bin: &out_link,
+            action: &self.to_string(),
+            install_bootloader: args.install_bootloader,
+            timeout: args.activation_timeout.map(Duration::from_secs),
+        };
+        let Self::DryActivate { json } = self else {
+            return activation.run(target);
+        };
+
+        let report = DryActivateReport::parse(&activation.output(target)?);
+        if *json {
+            let out = serde_json::to_string_pretty(&report)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            println!("{}", out);
+        } else {
+            print!("{}", report);
+        }
+        Ok(())
+    }
+
+    /// Builds the configuration, linking it as `<res_dir>/result`, and returns its store path.
+    ///
+    /// Once built, it is copied to wherever it is needed next: the target when it will be
+    /// activated, or this machine when it was built elsewhere.
+    fn build_configuration(
+        &self,
+        args: &AllArgs,
+        res_dir: &Utf8Path,
+        target: &Target,
+    ) -> io::Result<Utf8PathBuf> {
+        let extra_flags = args.nix_build_flags();
+        let build_ref = BuildRef::init(self, args)?;
+        build_ref.upgrade(args)?;
+
+        let Some(build_host) = &args.build_host else {
+            build_ref.run_nix_build(res_dir, &extra_flags, args.print_build_logs)?;
+            let toplevel = res_dir.join("result").canonicalize_utf8()?;
+            if self.activates() {
+                target.copy_closure(&toplevel, args.use_substitutes)?;
+            }
+            return Ok(toplevel);
+        };
+
+        let build_host = SshHost::connect(build_host)?;
+        let drv = build_ref.instantiate(&extra_flags)?;
+        NixCopy {
+            to: Some(&build_host),
+            derivation: true,
+            use_substitutes: args.use_substitutes,
+            ..NixCopy::default()
+        }
+        .run(&drv)?;
+        let toplevel = build_host.realise(&drv)?;
+
+        match target.remote() {
+            Some(target_host) if self.activates() => NixCopy {
+                from: Some(&build_host),
+                to: Some(target_host),
+                use_substitutes: args.use_substitutes,
+                ..NixCopy::default()
+            }
+            .run(&toplevel)?,
+            _ => {
+                NixCopy {
+                    from: Some(&build_host),
+                    use_substitutes: args.use_substitutes,
+                    ..NixCopy::default()
+                }
+                .run(&toplevel)?;
+                let res_link = res_dir.join("result");
+                utils::run(Command::new("nix-store").args([
+                    "--realise",
+                    toplevel.as_str(),
+                    "--add-root",
+                    res_link.as_str(),
+                ]))?;
+            }
+        }
+        Ok(toplevel)
+    }
+
+    /// Tasks that go on to `switch-to-configuration` once built
+    fn activates(&self) -> bool {
+        matches!(
+            self,
+            Self::Switch | Self::Boot | Self::Test | Self::DryActivate { .. }
+        )
+    }
+}
+
+/// What gets built: a flake output, or an attribute of a nix file
+enum BuildRef {
+    Flake(FlakeRef),
+    File(NixFileRef),
+}
+
+impl BuildRef {
+    fn init(task: &super::BuildSubComms, args: &AllArgs) -> io::Result<Self> {
+        if args.is_flake_build() {
+            return args.config.flake.init_flake_ref(task).map(Self::Flake);
+        }
+        NixFileRef::init(
+            args.config.file.as_deref(),
+            args.config.attr.as_deref(),
+            task,
+            &args.config.include,
+        )
+        .map(Self::File)
+    }
+
+    fn run_nix_build(
+        &self,
+        out_dir: &Utf8Path,
+        extra_flags: &[String],
+        print_build_logs: bool,
+    ) -> io::Result<()> {
+        match self {
+            Self::Flake(flake_ref) => {
+                flake_ref.run_nix_build(out_dir, extra_flags, print_build_logs)
+            }
+            Self::File(file_ref) => file_ref.run_nix_build(out_dir, extra_flags, print_build_logs),
+        }
+    }
+
+    /// `--upgrade`/`--upgrade-all`: a flake has its inputs updated, otherwise root's channels
+    /// on this machine are.
+    fn upgrade(&self, args: &AllArgs) -> io::Result<()> {
+        if !(args.upgrade || args.upgrade_all) {
+            return Ok(());
+        }
+        match self {
+            Self::Flake(flake_ref) => flake_ref.update_inputs(&Target::local(args.elevate)),
+            Self::File(_) => channel::upgrade(&Target::local(args.elevate), args.upgrade_all),
+        }
+    }
+
+    fn dry_run(&self, extra_flags: &[String]) -> io::Result<String> {
+        match self {
+            Self::Flake(flake_ref) => flake_ref.dry_run(extra_flags),
+            Self::File(file_ref) => file_ref.dry_run(extra_flags),
+        }
+    }
+
+    fn instantiate(&self, extra_flags: &[String]) -> io::Result<Utf8PathBuf> {
+        match self {
+            Self::Flake(flake_ref) => flake_ref.instantiate(extra_flags),
+            Self::File(file_ref) => file_ref.instantiate(extra_flags),
+        }
+    }
+}
+
+/// `<toplevel>/bin/switch-to-configuration`, or
+/// `<toplevel>/specialisation/<name>/bin/switch-to-configuration` when a specialisation is selected.
+///
+/// The selected specialisation must be present in the built configuration.
+fn switch_to_config_bin(
+    target: &Target,
+    toplevel: &Utf8Path,
+    specialisation: Option<&str>,
+) -> io::Result<Utf8PathBuf> {
+    let Some(spec) = specialisation else {
+        return Ok(toplevel.join("bin/switch-to-configuration"));
+    };
+
+    let available = specialisations(target, toplevel)?;
+    if !available.iter().any(|s| s == spec) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "Specialisation `{}` not found in {}. Available: [{}]",
+                spec,
+                toplevel,
+                available.join(", ")
+            ),
+        ));
+    }
+    Ok(toplevel
+        .join("specialisation")
+        .join(spec)
+        .join("bin/switch-to-configuration"))
+}
+
+/// Names of the entries in `<toplevel>/specialisation/`. A configuration without specialisations
+/// has no such directory.
+fn specialisations(target: &Target, toplevel: &Utf8Path) -> io::Result<Vec<String>> {
+    match target.read_dir_names(&toplevel.join("specialisation")) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elevate::Elevate;
+
+    #[test]
+    fn specialisation_bin() {
+        let td = tempfile::tempdir().unwrap();
+        let toplevel = Utf8Path::from_path(td.path()).unwrap();
+
+        assert_eq!(
+            switch_to_config_bin(&Target::local(Elevate::Auto), toplevel, None).unwrap(),
+            toplevel.join("bin/switch-to-configuration")
         );
-        log::trace!("Result link directory: {}", res_dir);
-        full_flake
-            .run_nix_build(res_dir.as_path())
-            .map(|_| (res_dir, use_td))
+        // no `specialisation/` dir at all
+        assert!(
+            switch_to_config_bin(&Target::local(Elevate::Auto), toplevel, Some("gaming")).is_err()
+        );
+
+        std::fs::create_dir_all(toplevel.join("specialisation/gaming")).unwrap();
+        std::fs::create_dir_all(toplevel.join("specialisation/work")).unwrap();
+        assert_eq!(
+            switch_to_config_bin(&Target::local(Elevate::Auto), toplevel, Some("gaming")).unwrap(),
+            toplevel.join("specialisation/gaming/bin/switch-to-configuration")
+        );
+        let err = switch_to_config_bin(&Target::local(Elevate::Auto), toplevel, Some("server"))
+            .unwrap_err();
+        assert!(err.to_string().contains("[gaming, work]"), "{}", err);
     }
 }
@@ -1,11 +1,14 @@
-use camino::{Utf8Path, Utf8PathBuf};
+use crate::{flake::FlakeRefInput, profile::Profile};
 
-use crate::flake::FlakeRefInput;
-
-#[allow(unused)]
-#[allow(clippy::unnecessary_wraps, reason = "result needed for parser")]
-pub(super) fn profile_name_parse(prof_name: &str) -> Result<Utf8PathBuf, String> {
-    Ok(Utf8Path::new("/nix/var/nix/profiles/system-profiles").join(prof_name))
+/// The name becomes a directory under `system-profiles`, so it has to be a single path component
+pub(super) fn profile_name_parse(prof_name: &str) -> Result<Profile, String> {
+    if matches!(prof_name, "" | "." | "..") || prof_name.contains('/') {
+        return Err(format!(
+            "Invalid profile name {:?}: must be a plain name, without '/'",
+            prof_name
+        ));
+    }
+    Ok(Profile::named(prof_name))
 }
 
 // TODO: this is needed for bringing in a value parser. if you can access `try_from` directly, do
@@ -13,3 +16,18 @@ pub(super) fn profile_name_parse(prof_name: &str) -> Result<Utf8PathBuf, String>
 pub(super) fn flake_parse(val: &str) -> Result<FlakeRefInput, String> {
     FlakeRefInput::try_from(val)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_name_is_one_component() {
+        for name in ["", ".", "..", "../system", "a/b", "/abs"] {
+            assert!(profile_name_parse(name).is_err(), "{:?}", name);
+        }
+        for name in ["system", "lab", "my.profile"] {
+            assert!(profile_name_parse(name).is_ok(), "{:?}", name);
+        }
+    }
+}
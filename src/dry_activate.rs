@@ -0,0 +1,174 @@
+//! What activating a configuration would change, as reported by
+//! `switch-to-configuration dry-activate`.
+use std::fmt::Display;
+
+use serde::Serialize;
+
+/// `nixos-rsbuild dry-activate`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DryActivateReport {
+    pub stop: Vec<String>,
+    pub restart: Vec<String>,
+    pub reload: Vec<String>,
+    pub start: Vec<String>,
+    /// systemd itself would be re-executed
+    pub restart_systemd: bool,
+    /// Changes that only take effect on reboot: changed units that won't be stopped
+    pub reboot_required: Vec<String>,
+    /// Any other output: the configuration's dry activation script, and warnings
+    pub activation: Vec<String>,
+}
+
+/// `<prefix>unit-a, unit-b`
+fn units(line: &str, prefix: &str) -> Option<Vec<String>> {
+    let units = line.strip_prefix(prefix)?;
+    Some(
+        units
+            .split(", ")
+            .map(str::trim)
+            .filter(|unit| !unit.is_empty())
+            .map(String::from)
+            .collect(),
+    )
+}
+
+impl DryActivateReport {
+    /// Sorts each line of output into the report. Lines that aren't about units are the dry
+    /// activation script's own.
+    pub fn parse(output: &str) -> Self {
+        let mut report = Self::default();
+
+        for line in output.lines().map(str::trim_end) {
+            if let Some(units) = units(line, "would stop the following units: ") {
+                report.stop.extend(units);
+            } else if let Some(units) = units(line, "would restart the following units: ") {
+                report.restart.extend(units);
+            } else if let Some(units) = units(line, "would reload the following units: ") {
+                report.reload.extend(units);
+            } else if let Some(units) = units(line, "would start the following units: ") {
+                report.start.extend(units);
+            } else if let Some(units) = units(line, "would NOT stop the following changed units: ")
+            {
+                report.reboot_required.extend(
+                    units
+                        .into_iter()
+                        .map(|unit| format!("{} changed, but would not be restarted", unit)),
+                );
+            } else if line == "would restart systemd" {
+                report.restart_systemd = true;
+            } else if !line.is_empty() && line != "would activate the configuration..." {
+                report.activation.push(line.to_string());
+            }
+        }
+        report
+    }
+
+    /// Nothing would be stopped, restarted, reloaded or started
+    pub fn is_unit_noop(&self) -> bool {
+        self.stop.is_empty()
+            && self.restart.is_empty()
+            && self.reload.is_empty()
+            && self.start.is_empty()
+            && !self.restart_systemd
+    }
+}
+
+impl Display for DryActivateReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_unit_noop() {
+            writeln!(
+                f,
+                "No units would be stopped, restarted, reloaded or started"
+            )?;
+        }
+        if self.restart_systemd {
+            writeln!(f, "Would restart systemd")?;
+        }
+        for (verb, units) in [
+            ("stop", &self.stop),
+            ("restart", &self.restart),
+            ("reload", &self.reload),
+            ("start", &self.start),
+        ] {
+            if !units.is_empty() {
+                writeln!(f, "Would {} ({}): {}", verb, units.len(), units.join(", "))?;
+            }
+        }
+        for (heading, lines) in [
+            ("Reboot required", &self.reboot_required),
+            ("Other output", &self.activation),
+        ] {
+            if !lines.is_empty() {
+                writeln!(f, "{}:", heading)?;
+                for line in lines {
+                    writeln!(f, "  {}", line)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DRY_ACTIVATE: &str = include_str!("../tests/fixtures/dry-activate.txt");
+
+    #[test]
+    fn parse_dry_activate() {
+        let report = DryActivateReport::parse(DRY_ACTIVATE);
+        assert_eq!(report.stop, ["grafana.service", "prometheus.service"]);
+        assert_eq!(report.restart, ["nginx.service", "sshd.service"]);
+        assert_eq!(report.reload, ["dbus.service"]);
+        assert_eq!(report.start, ["loki.service"]);
+        assert!(report.restart_systemd);
+        assert_eq!(
+            report.reboot_required,
+            ["systemd-journald.service changed, but would not be restarted"]
+        );
+        assert_eq!(
+            report.activation,
+            ["setting up /etc...", "would create user 'loki'"]
+        );
+    }
+
+    #[test]
+    fn reboot_mentions_are_activation_output() {
+        let report = DryActivateReport::parse(
+            "would NOT stop the following changed units: dbus.service\n\
+             warning: a reboot is needed to load the new kernel modules\n",
+        );
+        assert_eq!(
+            report.reboot_required,
+            ["dbus.service changed, but would not be restarted"]
+        );
+        assert_eq!(
+            report.activation,
+            ["warning: a reboot is needed to load the new kernel modules"]
+        );
+    }
+
+    #[test]
+    fn summary() {
+        let report = DryActivateReport::parse(DRY_ACTIVATE);
+        assert_eq!(
+            report.to_string(),
+            "Would restart systemd
+Would stop (2): grafana.service, prometheus.service
+Would restart (2): nginx.service, sshd.service
+Would reload (1): dbus.service
+Would start (1): loki.service
+Reboot required:
+  systemd-journald.service changed, but would not be restarted
+Other output:
+  setting up /etc...
+  would create user 'loki'
+"
+        );
+        assert_eq!(
+            DryActivateReport::default().to_string(),
+            "No units would be stopped, restarted, reloaded or started\n"
+        );
+    }
+}
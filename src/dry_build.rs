@@ -0,0 +1,333 @@
+//! What a build would do, without doing it: the `--dry-run` report of nix, with per-path sizes
+//! looked up in the substituters.
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    process::{Command, Stdio},
+};
+
+use serde::Serialize;
+
+use crate::utils::human_bytes;
+
+/// A store path that would be substituted, rather than built
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FetchedPath {
+    pub path: String,
+    /// Compressed size, as downloaded. `None` when no substituter could tell.
+    pub download_size: Option<u64>,
+    /// Size once unpacked into the store. `None` when no substituter could tell.
+    pub unpacked_size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DryBuildTotal {
+    pub build: usize,
+    pub fetch: usize,
+    /// `None` when nix reported none, or in a form that couldn't be read
+    pub download_size: Option<u64>,
+    pub unpacked_size: Option<u64>,
+}
+
+/// `nixos-rsbuild dry-build`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DryBuildReport {
+    /// Derivations that would be built
+    pub build: Vec<String>,
+    pub fetch: Vec<FetchedPath>,
+    pub total: DryBuildTotal,
+}
+
+/// Which list of store paths is being read
+enum Section {
+    None,
+    Build,
+    Fetch,
+}
+
+impl DryBuildReport {
+    /// Parses what `nix build --dry-run` and `nix-build --dry-run` print to stderr:
+    ///
+    /// ```text
+    /// these 2 derivations will be built:
+    ///   /nix/store/<hash>-foo.drv
+    /// these 3 paths will be fetched (12.34 MiB download, 56.78 MiB unpacked):
+    ///   /nix/store/<hash>-bar
+    /// ```
+    ///
+    /// Anything else (warnings, evaluation messages) is skipped, as are sizes that can't be read.
+    pub fn parse(dry_run: &str) -> Self {
+        let mut report = Self::default();
+        let mut section = Section::None;
+
+        for line in dry_run.lines() {
+            if let Some(path) = line.strip_prefix("  ").map(str::trim) {
+                match section {
+                    Section::Build => report.build.push(path.to_string()),
+                    Section::Fetch => report.fetch.push(FetchedPath {
+                        path: path.to_string(),
+                        download_size: None,
+                        unpacked_size: None,
+                    }),
+                    Section::None => {}
+                }
+                continue;
+            }
+
+            section = if line.contains(" will be built:") {
+                Section::Build
+            } else if line.contains(" will be fetched") {
+                match parse_fetch_sizes(line) {
+                    Some((download, unpacked)) => {
+                        report.total.download_size = Some(download);
+                        report.total.unpacked_size = Some(unpacked);
+                    }
+                    None => log::warn!("Could not parse download sizes from: {}", line),
+                }
+                Section::Fetch
+            } else {
+                Section::None
+            };
+        }
+
+        report.total.build = report.build.len();
+        report.total.fetch = report.fetch.len();
+        report
+    }
+
+    /// Looks up the sizes of the paths to fetch, in each substituter in turn. Lookups are best
+    /// effort: paths no substituter could size are left as `None`.
+    pub fn fill_sizes(&mut self, substituters: &[String]) {
+        for store in substituters {
+            let missing = self
+                .fetch
+                .iter()
+                .filter(|fetched| fetched.download_size.is_none())
+                .map(|fetched| fetched.path.as_str())
+                .collect::<Vec<_>>();
+            if missing.is_empty() {
+                return;
+            }
+
+            let out = Command::new("nix")
+                .args(["path-info", "--json", "--store", store])
+                .args(&missing)
+                .stderr(Stdio::null())
+                .output();
+            let sizes = match out {
+                Ok(out) => parse_path_info(&String::from_utf8_lossy(&out.stdout)),
+                Err(e) => {
+                    log::warn!("Could not query sizes from {}: {}", store, e);
+                    continue;
+                }
+            };
+            for fetched in &mut self.fetch {
+                if let Some((download, unpacked)) = sizes.get(&fetched.path) {
+                    fetched.download_size = *download;
+                    fetched.unpacked_size = *unpacked;
+                }
+            }
+        }
+    }
+}
+
+/// `... (12.34 MiB download, 56.78 MiB unpacked):` -> bytes of each. `None` if the sizes aren't
+/// in a form nix is known to print them in.
+fn parse_fetch_sizes(line: &str) -> Option<(u64, u64)> {
+    let (_, sizes) = line.split_once('(')?;
+    let (sizes, _) = sizes.split_once(')')?;
+
+    let mut download = None;
+    let mut unpacked = None;
+    for size in sizes.split(", ") {
+        let mut words = size.split_whitespace();
+        let (Some(num), Some(unit), Some(kind)) = (words.next(), words.next(), words.next()) else {
+            return None;
+        };
+        let scale = match unit {
+            "KiB" => 1024.0,
+            "MiB" => 1024.0 * 1024.0,
+            "GiB" => 1024.0 * 1024.0 * 1024.0,
+            "TiB" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+            _ => return None,
+        };
+        let bytes = num.parse::<f64>().ok()? * scale;
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let bytes = bytes.round() as u64;
+        match kind {
+            "download" => download = Some(bytes),
+            "unpacked" => unpacked = Some(bytes),
+            _ => return None,
+        }
+    }
+    Some((download.unwrap_or(0), unpacked.unwrap_or(0)))
+}
+
+/// `(downloadSize, narSize)` by path, out of `nix path-info --json`. Older nix lists objects with
+/// a `path`, newer nix maps paths to objects, or to `null` when not found.
+fn parse_path_info(json: &str) -> HashMap<String, (Option<u64>, Option<u64>)> {
+    let sizes = |info: &serde_json::Value| {
+        (
+            info.get("downloadSize").and_then(serde_json::Value::as_u64),
+            info.get("narSize").and_then(serde_json::Value::as_u64),
+        )
+    };
+
+    match serde_json::from_str(json) {
+        Ok(serde_json::Value::Array(infos)) => infos
+            .iter()
+            .filter(|info| info.get("valid") != Some(&serde_json::Value::Bool(false)))
+            .filter_map(|info| Some((info.get("path")?.as_str()?.to_string(), sizes(info))))
+            .collect(),
+        Ok(serde_json::Value::Object(infos)) => infos
+            .iter()
+            .filter(|(_, info)| !info.is_null())
+            .map(|(path, info)| (path.clone(), sizes(info)))
+            .collect(),
+        _ => HashMap::new(),
+    }
+}
+
+/// `/nix/store/<hash>-hello-2.12.1.drv` -> `hello-2.12.1.drv`
+fn store_name(path: &str) -> &str {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    name.split_once('-').map_or(name, |(_hash, name)| name)
+}
+
+fn size_cell(size: Option<u64>) -> String {
+    size.map_or_else(|| "?".to_string(), human_bytes)
+}
+
+impl Display for DryBuildReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.build.is_empty() {
+            writeln!(f, "To build ({}):", self.build.len())?;
+            for drv in &self.build {
+                writeln!(f, "  {}", store_name(drv))?;
+            }
+        }
+        if !self.fetch.is_empty() {
+            let width = self
+                .fetch
+                .iter()
+                .map(|fetched| store_name(&fetched.path).len())
+                .max()
+                .unwrap_or(0);
+            writeln!(f, "To fetch ({}):", self.fetch.len())?;
+            writeln!(
+                f,
+                "  {:width$}  {:>12}  {:>12}",
+                "PATH", "DOWNLOAD", "UNPACKED"
+            )?;
+            for fetched in &self.fetch {
+                writeln!(
+                    f,
+                    "  {:width$}  {:>12}  {:>12}",
+                    store_name(&fetched.path),
+                    size_cell(fetched.download_size),
+                    size_cell(fetched.unpacked_size),
+                )?;
+            }
+        }
+        write!(
+            f,
+            "Total: {} to build, {} to fetch, {} download, {} unpacked",
+            self.total.build,
+            self.total.fetch,
+            size_cell(self.total.download_size),
+            size_cell(self.total.unpacked_size),
+        )
+    }
+}
+
+/// The substituters nix is configured with, as per `nix config show substituters`
+///
+/// # Errors
+///
+/// If nix could not be queried
+pub fn substituters() -> io::Result<Vec<String>> {
+    let out = crate::utils::output(Command::new("nix").args(["config", "show", "substituters"]))?;
+    Ok(out.split_whitespace().map(String::from).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DRY_RUN: &str = include_str!("../tests/fixtures/dry-build.txt");
+
+    #[test]
+    fn parse_dry_run() {
+        let report = DryBuildReport::parse(DRY_RUN);
+        assert_eq!(
+            report.build,
+            [
+                "/nix/store/0c6a-hello-2.12.1.drv",
+                "/nix/store/4kq1-nixos-system-lab-24.11.drv"
+            ]
+        );
+        assert_eq!(report.fetch.len(), 3);
+        assert_eq!(report.fetch[2].path, "/nix/store/x8n2-linux-6.6.52");
+        assert_eq!(
+            report.total,
+            DryBuildTotal {
+                build: 2,
+                fetch: 3,
+                download_size: Some(12_939_428),
+                unpacked_size: Some(59_538_145),
+            }
+        );
+
+        let nothing = DryBuildReport::parse("warning: Git tree '/etc/nixos' is dirty\n");
+        assert_eq!(nothing, DryBuildReport::default());
+        let unreadable = DryBuildReport::parse("these 3 paths will be fetched (lots):");
+        assert_eq!(unreadable.total.download_size, None);
+        assert_eq!(unreadable.total.unpacked_size, None);
+    }
+
+    #[test]
+    fn fetch_size_units() {
+        let kib = DryBuildReport::parse(include_str!("../tests/fixtures/dry-build-kib.txt"));
+        assert_eq!(kib.total.fetch, 1);
+        assert_eq!(kib.total.download_size, Some(12_636));
+        assert_eq!(kib.total.unpacked_size, Some(52_634));
+
+        let gib = DryBuildReport::parse(include_str!("../tests/fixtures/dry-build-gib.txt"));
+        assert_eq!(gib.total.fetch, 2);
+        assert_eq!(gib.total.download_size, Some(1_621_350_154));
+        assert_eq!(gib.total.unpacked_size, Some(5_798_205_850));
+    }
+
+    #[test]
+    fn path_info_formats() {
+        let listed = r#"[{"path":"/nix/store/aaa-a","narSize":300,"downloadSize":100},
+            {"path":"/nix/store/bbb-b","valid":false}]"#;
+        let mapped = r#"{"/nix/store/aaa-a":{"narSize":300,"downloadSize":100},
+            "/nix/store/bbb-b":null}"#;
+        for json in [listed, mapped] {
+            let sizes = parse_path_info(json);
+            assert_eq!(sizes.len(), 1, "{}", json);
+            assert_eq!(sizes["/nix/store/aaa-a"], (Some(100), Some(300)));
+        }
+    }
+
+    #[test]
+    fn table() {
+        let mut report = DryBuildReport::parse(DRY_RUN);
+        report.fetch[0].download_size = Some(1024 * 1024);
+        report.fetch[0].unpacked_size = Some(4 * 1024 * 1024);
+        assert_eq!(
+            report.to_string(),
+            "To build (2):
+  hello-2.12.1.drv
+  nixos-system-lab-24.11.drv
+To fetch (3):
+  PATH               DOWNLOAD      UNPACKED
+  glibc-2.40-36       1.0 MiB       4.0 MiB
+  openssl-3.3.2             ?             ?
+  linux-6.6.52              ?             ?
+Total: 2 to build, 3 to fetch, 12.3 MiB download, 56.8 MiB unpacked"
+        );
+    }
+}
@@ -0,0 +1,61 @@
+use clap::ValueEnum;
+
+/// How root privileges are obtained on the target, for registering generations and activating
+/// them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, strum::Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Elevate {
+    /// The first of `sudo`, `doas` and `run0` the target has
+    #[default]
+    Auto,
+    Sudo,
+    Doas,
+    /// systemd's `run0`
+    Run0,
+    /// polkit's `pkexec`
+    Pkexec,
+    /// Run directly, e.g. when root is obtained some other way
+    None,
+}
+
+/// What `auto` looks for on the target, in order
+pub const AUTO_PROGRAMS: [&str; 3] = ["sudo", "doas", "run0"];
+
+impl Elevate {
+    /// The program to prefix a command with, if any. Escalation is skipped when already root,
+    /// whichever method is picked. `is_root` may well involve a round-trip to a remote machine, so
+    /// `none` doesn't consult it. Nor is `detect`, which finds the program for `auto`, consulted
+    /// unless needed.
+    /// ```
+    /// use nixos_rsbuild::elevate::Elevate;
+    /// let root = || Ok::<_, ()>(true);
+    /// let user = || Ok::<_, ()>(false);
+    /// let has_doas = || Ok("doas");
+    /// assert_eq!(Elevate::Auto.program(user, has_doas), Ok(Some("doas")));
+    /// assert_eq!(Elevate::Auto.program(root, has_doas), Ok(None));
+    /// assert_eq!(Elevate::Sudo.program(user, has_doas), Ok(Some("sudo")));
+    /// assert_eq!(Elevate::Doas.program(root, has_doas), Ok(None));
+    /// assert_eq!(Elevate::None.program(user, has_doas), Ok(None));
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// If `is_root` or `detect` was consulted, and failed
+    pub fn program<E>(
+        self,
+        is_root: impl FnOnce() -> Result<bool, E>,
+        detect: impl FnOnce() -> Result<&'static str, E>,
+    ) -> Result<Option<&'static str>, E> {
+        if self == Self::None || is_root()? {
+            return Ok(None);
+        }
+        Ok(match self {
+            Self::Auto => Some(detect()?),
+            Self::Sudo => Some("sudo"),
+            Self::Doas => Some("doas"),
+            Self::Run0 => Some("run0"),
+            Self::Pkexec => Some("pkexec"),
+            Self::None => None,
+        })
+    }
+}
@@ -1,16 +1,12 @@
 use camino::{Utf8Path, Utf8PathBuf};
 use flake_path::FlakeDir;
-use std::{
-    ffi::OsStr,
-    fmt::Display,
-    io::{self, ErrorKind},
-};
+use std::{ffi::OsStr, fmt::Display, io, process::Command};
 
 mod attribute;
 mod flake_path;
 pub use attribute::FlakeAttr;
 
-use crate::cmd::BuildSubComms;
+use crate::{cmd::BuildSubComms, nix_log, remote::Target, repl, utils};
 
 /// Destructured `<flake_dir>[#attribute]`
 #[derive(Debug, Clone)]
@@ -56,20 +52,148 @@ impl Display for FlakeRef {
 }
 
 impl FlakeRef {
-    pub fn run_nix_build(&self, out_dir: &Utf8Path) -> io::Result<()> {
+    /// `nix build`, rendering its progress. `print_build_logs` shows the build logs in full.
+    pub fn run_nix_build(
+        &self,
+        out_dir: &Utf8Path,
+        extra_flags: &[String],
+        print_build_logs: bool,
+    ) -> io::Result<()> {
         log::info!("Building in flake mode.");
 
-        let refstr = self.to_string();
-        let resfile = out_dir.join("result");
-        cmd_lib::spawn!(nix  build "$refstr" --out-link "$resfile")
-            .unwrap()
-            .wait()
-            .unwrap();
-        Ok(())
+        let mut cmd = Command::new("nix");
+        cmd.arg("build")
+            .arg(self.to_string())
+            .arg("--out-link")
+            .arg(out_dir.join("result"))
+            .args(extra_flags);
+        nix_log::run_with_progress(&mut cmd, print_build_logs)
     }
+
+    /// Updates all inputs in the flake's lock file: `nix flake update`, run in the flake's dir
+    /// rather than pointed at it with `--flake`, which needs nix 2.19 or later. Runs as the
+    /// invoking user, or elevated on `target` when they can't write the lock file, as with a
+    /// root-owned `/etc/nixos`.
+    pub fn update_inputs(&self, target: &Target) -> io::Result<()> {
+        log::info!("Updating flake inputs of {}", self.source);
+
+        let dir = self.source.as_ref();
+        let argv = ["nix", "flake", "update"];
+        let mut cmd = if is_writable(&dir.join("flake.lock")) {
+            target.command(&argv)
+        } else {
+            log::info!("Lock file of {} is not writable; updating elevated", dir);
+            target.elevated_command(&argv)?
+        };
+        utils::run(cmd.current_dir(dir))
+    }
+
+    /// `nix build --dry-run`, returning what it reports on stderr
+    pub fn dry_run(&self, extra_flags: &[String]) -> io::Result<String> {
+        log::info!("Dry-running build in flake mode.");
+
+        let mut cmd = Command::new("nix");
+        cmd.arg("build")
+            .arg(self.to_string())
+            .arg("--dry-run")
+            .args(extra_flags);
+        utils::stderr_output(&mut cmd)
+    }
+
+    /// Evaluates the derivation without building it: `nix eval --raw <flake-ref>.drvPath`
+    pub fn instantiate(&self, extra_flags: &[String]) -> io::Result<Utf8PathBuf> {
+        log::info!("Evaluating derivation in flake mode.");
+
+        let mut cmd = Command::new("nix");
+        cmd.args(["eval", "--raw"])
+            .arg(format!("{}.drvPath", self))
+            .args(extra_flags);
+        let drv = utils::output(&mut cmd)?;
+        Ok(Utf8PathBuf::from(drv.trim()))
+    }
+}
+
+/// Whether the file can be written to, or created when missing
+fn is_writable(path: &Utf8Path) -> bool {
+    let existing = if path.exists() {
+        path
+    } else {
+        path.parent().unwrap_or(Utf8Path::new("."))
+    };
+    nix::unistd::access(existing.as_std_path(), nix::unistd::AccessFlags::W_OK).is_ok()
 }
 
 impl FlakeRefInput {
+    /// The file defining the host's configuration, for editing. Found with
+    /// `builtins.unsafeGetAttrPos` on `nixosConfigurations.<host>`, and mapped from the flake's
+    /// store copy back to the working tree.
+    ///
+    /// Falls back to the flake's `flake.nix`, when the position cannot be determined.
+    /// `eval_flags` are passed to `nix eval`, e.g. `--impure` and `--override-input`.
+    ///
+    /// # Errors
+    ///
+    /// If the flake directory could not be resolved
+    pub fn host_definition(&self, eval_flags: &[String]) -> io::Result<Utf8PathBuf> {
+        let dir = FlakeDir::try_from_path(&self.source)?;
+        let flake_nix = dir.as_ref().join("flake.nix");
+
+        let mut attr = self
+            .output_selector
+            .clone()
+            .unwrap_or(FlakeAttr::try_default()?);
+        attr.set_config()?;
+        let Some((host, parent)) = attr.attr_path.split_last() else {
+            return Ok(flake_nix);
+        };
+
+        let parent_ref = format!("{}#{}", dir, parent.join("."));
+        let apply = format!(
+            r#"set: let pos = builtins.unsafeGetAttrPos "{}" set; in if pos == null then "" else pos.file"#,
+            host
+        );
+        let mut cmd = Command::new("nix");
+        cmd.args(["eval", "--raw", &parent_ref, "--apply", &apply])
+            .args(eval_flags);
+        let store_file = match utils::output(&mut cmd) {
+            Ok(file) if !file.is_empty() => file,
+            Ok(_) => {
+                log::warn!("No position for {}, opening flake.nix", attr);
+                return Ok(flake_nix);
+            }
+            Err(e) => {
+                log::warn!("Could not locate {}, opening flake.nix: {}", attr, e);
+                return Ok(flake_nix);
+            }
+        };
+        Ok(source_file(dir.as_ref(), &store_file).unwrap_or(flake_nix))
+    }
+
+    /// The host's configuration for `nix repl`: `nixosConfigurations.<host>`, defaulting the host
+    /// the same way building does
+    ///
+    /// # Errors
+    ///
+    /// If the flake directory could not be resolved
+    pub fn host_repl(&self) -> io::Result<repl::HostRepl> {
+        let dir = FlakeDir::try_from_path(&self.source)?;
+
+        let mut attr = self
+            .output_selector
+            .clone()
+            .unwrap_or(FlakeAttr::try_default()?);
+        attr.set_config()?;
+
+        Ok(repl::HostRepl {
+            host: format!(
+                "(builtins.getFlake \"{}\"){}",
+                dir,
+                repl::attr_selector(&attr.attr_path)
+            ),
+            nixos_config: None,
+        })
+    }
+
     /// nixos-rsbuild will flakebuild, unless explicitly stated with the --no-flake flag
     ///
     /// # No path stated in flake-ref
@@ -97,17 +221,7 @@ impl FlakeRefInput {
             "system".to_string(),
             "build".to_string(),
         ]);
-        let last_attr = match task {
-            BuildSubComms::Switch
-            | BuildSubComms::Boot
-            | BuildSubComms::Test
-            | BuildSubComms::DryBuild
-            | BuildSubComms::Build
-            | BuildSubComms::DryActivate => "toplevel",
-            BuildSubComms::BuildVm => "vm",
-            BuildSubComms::BuildVmWithBootloader => "vmWithBootLoader",
-        };
-        attr.attr_path.push(last_attr.to_string());
+        attr.attr_path.push(task.build_output().to_string());
 
         Ok(FlakeRef {
             source: path,
@@ -121,19 +235,19 @@ impl FlakeRefInput {
         let dir =
             Utf8PathBuf::from_path_buf(std::fs::canonicalize(crate::utils::DEFAULT_FLAKE_NIX)?)
                 .map_err(|e| {
-                    io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("Canonicalised path {} not valid Utf8", e.display()),
-                    )
+                    io::Error::other(format!("Canonicalised path {} not valid Utf8", e.display()))
                 })?;
         if dir.is_dir() {
-            Err(io::Error::new(ErrorKind::Other, format!("Canonical path from default flake.nix should resolve to a flake.nix. Resolved to: {}", dir)))
+            Err(io::Error::other(format!("Canonical path from default flake.nix should resolve to a flake.nix. Resolved to: {}", dir)))
         } else if dir
             .file_name()
             .expect("somehow symlink of default flake.nix resolved to `..`")
             != OsStr::new("flake.nix")
         {
-            Err(io::Error::new(ErrorKind::Other, format!("Canonical path from default flake.nix resolved to file other than `flake.nix`: {}", dir)))
+            Err(io::Error::other(format!(
+                "Canonical path from default flake.nix resolved to file other than `flake.nix`: {}",
+                dir
+            )))
         } else {
             Ok(dir)
         }
@@ -175,6 +289,17 @@ impl TryFrom<&str> for FlakeRefInput {
     }
 }
 
+/// Maps a file in the store copy of a flake (`/nix/store/<hash>-source/<rel>`) back to the
+/// working tree. The copy is of the whole repository, so `<rel>` may be relative to an ancestor
+/// of the flake directory.
+fn source_file(flake_dir: &Utf8Path, store_file: &str) -> Option<Utf8PathBuf> {
+    let (_source, rel) = store_file.strip_prefix("/nix/store/")?.split_once('/')?;
+    flake_dir
+        .ancestors()
+        .map(|dir| dir.join(rel))
+        .find(|candidate| candidate.is_file())
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -218,4 +343,39 @@ mod tests {
         assert!(FlakeRefInput::try_from("/fizz/buzz#foo#").is_err());
         assert!(FlakeRefInput::try_from(r#"/fizz/buzz#foo""#).is_err());
     }
+
+    #[test]
+    fn source_file_in_working_tree() {
+        let td = tempfile::tempdir().unwrap();
+        let repo = Utf8Path::from_path(td.path()).unwrap();
+        std::fs::create_dir_all(repo.join("nixos")).unwrap();
+        std::fs::create_dir_all(repo.join("hosts")).unwrap();
+        std::fs::write(repo.join("nixos/flake.nix"), "").unwrap();
+        std::fs::write(repo.join("hosts/lab.nix"), "").unwrap();
+        let flake_dir = repo.join("nixos");
+
+        assert_eq!(
+            source_file(&flake_dir, "/nix/store/0c6a-source/nixos/flake.nix"),
+            Some(flake_dir.join("flake.nix"))
+        );
+        assert_eq!(
+            source_file(&flake_dir, "/nix/store/0c6a-source/hosts/lab.nix"),
+            Some(repo.join("hosts/lab.nix"))
+        );
+        assert_eq!(
+            source_file(&flake_dir, "/nix/store/0c6a-source/hosts/gone.nix"),
+            None
+        );
+    }
+
+    #[test]
+    fn lock_file_writable() {
+        let td = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(td.path()).unwrap();
+        // Missing, but can be created
+        assert!(is_writable(&dir.join("flake.lock")));
+        std::fs::write(dir.join("flake.lock"), "{}").unwrap();
+        assert!(is_writable(&dir.join("flake.lock")));
+        assert!(!is_writable(&dir.join("gone/flake.lock")));
+    }
 }
@@ -30,9 +30,7 @@ impl FlakeAttr {
         let machine_name: String = hostname::get()
             .unwrap_or(OsString::from("default"))
             .into_string()
-            .map_err(|_os| {
-                io::Error::new(io::ErrorKind::Other, "Could not read utf8-valid hostname")
-            })?;
+            .map_err(|_os| io::Error::other("Could not read utf8-valid hostname"))?;
         log::info!("pushing {} attr", machine_name);
         self.attr_path.push(machine_name);
         log::trace!("Flake attr: {}", self);
@@ -59,12 +57,9 @@ impl FlakeAttr {
         self.attr_path.is_empty()
     }
     pub fn try_default() -> io::Result<Self> {
-        let attr = hostname::get()?.into_string().map_err(|_| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                "hostname read gave non-utf8 result".to_string(),
-            )
-        })?;
+        let attr = hostname::get()?
+            .into_string()
+            .map_err(|_| io::Error::other("hostname read gave non-utf8 result".to_string()))?;
         Ok(Self {
             attr_path: vec!["nixosConfigurations".to_string(), attr],
         })
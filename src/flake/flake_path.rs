@@ -31,74 +31,52 @@ impl FlakeDir<Utf8PathBuf> {
     /// contained flake.nix links to regular file named `flake.nix`: said files parent directory
     pub fn try_from_path<T: AsRef<Utf8Path>>(value: T) -> io::Result<Self> {
         if !value.as_ref().is_dir() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Is not a dir: {}", value.as_ref()),
-            ));
+            return Err(io::Error::other(format!(
+                "Is not a dir: {}",
+                value.as_ref()
+            )));
         }
 
         let flake_loc = value.as_ref().join("flake.nix");
         let flake_exists = std::fs::exists(&flake_loc).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "Error when checking for existence of flake at {}: {}",
-                    flake_loc, e
-                ),
-            )
+            io::Error::other(format!(
+                "Error when checking for existence of flake at {}: {}",
+                flake_loc, e
+            ))
         })?;
 
         if !flake_exists {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "flake-path must be a directory containing `flake.nix`: {}.",
-                    value.as_ref()
-                ),
-            ));
+            return Err(io::Error::other(format!(
+                "flake-path must be a directory containing `flake.nix`: {}.",
+                value.as_ref()
+            )));
         }
 
         let canoned_path = std::fs::canonicalize(&flake_loc).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Could not canonicalise path {}: {}", flake_loc, e),
-            )
+            io::Error::other(format!("Could not canonicalise path {}: {}", flake_loc, e))
         })?;
 
         if canoned_path.is_dir() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "Sym-link from {} must resolve to `flake.nix`. Resolved to a directory: {}",
-                    flake_loc,
-                    canoned_path.display()
-                ),
-            ));
+            return Err(io::Error::other(format!(
+                "Sym-link from {} must resolve to `flake.nix`. Resolved to a directory: {}",
+                flake_loc,
+                canoned_path.display()
+            )));
         }
         if canoned_path.file_name() != Some(OsStr::new("flake.nix")) {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "Sym-link from {} must resolve to a `flake.nix`. Resolved to: {}",
-                    flake_loc,
-                    canoned_path.display()
-                ),
-            ));
+            return Err(io::Error::other(format!(
+                "Sym-link from {} must resolve to a `flake.nix`. Resolved to: {}",
+                flake_loc,
+                canoned_path.display()
+            )));
         }
 
-        let res = canoned_path.parent().ok_or(io::Error::new(
-            io::ErrorKind::Other,
-            format!(
-                "Could not resolve to directory from {}",
-                canoned_path.display()
-            ),
-        ))?;
-        let res = Utf8PathBuf::from_path_buf(res.to_path_buf()).map_err(|_e| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Invalid utf8: {}", res.display()),
-            )
-        })?;
+        let res = canoned_path.parent().ok_or(io::Error::other(format!(
+            "Could not resolve to directory from {}",
+            canoned_path.display()
+        )))?;
+        let res = Utf8PathBuf::from_path_buf(res.to_path_buf())
+            .map_err(|_e| io::Error::other(format!("Invalid utf8: {}", res.display())))?;
 
         Ok(Self { canoned_dir: res })
     }
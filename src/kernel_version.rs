@@ -0,0 +1,227 @@
+//! Kernel versions as they name a kernel's `lib/modules/<version>` dir. Unlike semver, these may
+//! have two components (`6.10`), be a release candidate (`6.10.0-rc3`), or carry the suffix of a
+//! patched kernel (`6.6.52-zen1`, `6.1.112-hardened1`, `6.6.52_xanmod1`).
+use std::{cmp::Ordering, fmt::Display, io, path::Path, str::FromStr};
+
+use serde::{Serialize, Serializer};
+
+use crate::closure_diff::compare_versions;
+
+#[derive(Debug, Clone)]
+pub struct KernelVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: Option<u32>,
+    /// `-rc<N>`
+    pub rc: Option<u32>,
+    /// Whatever follows the version and release candidate, e.g. `zen1`
+    pub local: Option<String>,
+    /// As parsed, for display
+    raw: String,
+}
+
+impl KernelVersion {
+    /// The patch set the kernel was built with: its suffix, without a trailing revision.
+    ///
+    /// ```
+    /// use nixos_rsbuild::kernel_version::KernelVersion;
+    /// let ver = "6.6.52-zen1".parse::<KernelVersion>().unwrap();
+    /// assert_eq!(ver.variant(), Some("zen"));
+    /// let ver = "6.6.52_xanmod1".parse::<KernelVersion>().unwrap();
+    /// assert_eq!(ver.variant(), Some("xanmod"));
+    /// assert_eq!("6.6.52".parse::<KernelVersion>().unwrap().variant(), None);
+    /// ```
+    pub fn variant(&self) -> Option<&str> {
+        self.local
+            .as_deref()
+            .map(|local| local.trim_end_matches(|c: char| c.is_ascii_digit()))
+            .map(|name| name.trim_end_matches(['-', '_', '.']))
+            .filter(|name| !name.is_empty())
+    }
+
+    /// The version of the kernel under `<kernel_dir>/lib/modules`. Where there are several
+    /// modules dirs, the newest version is taken, so that the choice doesn't depend on the
+    /// order the filesystem lists them in.
+    ///
+    /// # Errors
+    ///
+    /// If the dir can't be read, or holds no dir named after a kernel version
+    pub fn from_modules_dir(kernel_dir: &Path) -> io::Result<Self> {
+        let modules = kernel_dir.join("lib/modules");
+        let mut versions = Vec::new();
+        for entry in std::fs::read_dir(&modules)? {
+            let name = entry?.file_name();
+            match name.to_str().map(str::parse::<Self>) {
+                Some(Ok(ver)) => versions.push(ver),
+                Some(Err(e)) => log::debug!("skipping {}: {}", modules.display(), e),
+                None => log::debug!("skipping non-utf8 dir in {}", modules.display()),
+            }
+        }
+        versions.into_iter().max().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No kernel version dir in {}", modules.display()),
+            )
+        })
+    }
+
+    /// Sort key, but for the suffix: release candidates come before their release
+    fn key(&self) -> (u32, u32, u32, bool, u32) {
+        (
+            self.major,
+            self.minor,
+            self.patch.unwrap_or(0),
+            self.rc.is_none(),
+            self.rc.unwrap_or(0),
+        )
+    }
+}
+
+/// Splits off a leading run of digits: `52-zen1` -> `(52, "-zen1")`
+fn leading_number(val: &str) -> Option<(u32, &str)> {
+    let end = val.find(|c: char| !c.is_ascii_digit()).unwrap_or(val.len());
+    Some((val[..end].parse().ok()?, &val[end..]))
+}
+
+impl FromStr for KernelVersion {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "expected <major>.<minor>[.<patch>][-rc<N>][-<suffix>], got `{}`",
+                val
+            )
+        };
+
+        let (major, rest) = leading_number(val).ok_or_else(invalid)?;
+        let (minor, mut rest) = rest
+            .strip_prefix('.')
+            .and_then(leading_number)
+            .ok_or_else(invalid)?;
+        let mut patch = None;
+        if let Some((num, after)) = rest.strip_prefix('.').and_then(leading_number) {
+            patch = Some(num);
+            rest = after;
+        }
+
+        let mut rc = None;
+        if let Some((num, after)) = rest.strip_prefix("-rc").and_then(leading_number) {
+            rc = Some(num);
+            rest = after;
+        }
+
+        let local = rest.trim_start_matches(['-', '_', '.', '+']);
+        if local.len() == rest.len() && !rest.is_empty() {
+            // e.g. `6.6a`: the suffix must be separated from the version
+            return Err(invalid());
+        }
+        Ok(Self {
+            major,
+            minor,
+            patch,
+            rc,
+            local: (!local.is_empty()).then(|| local.to_string()),
+            raw: val.to_string(),
+        })
+    }
+}
+
+impl Display for KernelVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+impl Serialize for KernelVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl PartialEq for KernelVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for KernelVersion {}
+
+impl PartialOrd for KernelVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for KernelVersion {
+    /// Unpatched kernels come before patched ones. Suffixes are compared as versions, so that
+    /// `zen2` comes before `zen10`.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key()
+            .cmp(&other.key())
+            .then_with(|| match (&self.local, &other.local) {
+                (Some(lhs), Some(rhs)) => compare_versions(lhs, rhs),
+                (lhs, rhs) => lhs.cmp(rhs),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_kernel_versions() {
+        let parse = |val: &str| {
+            let ver = val.parse::<KernelVersion>().unwrap();
+            assert_eq!(ver.to_string(), val);
+            (ver.major, ver.minor, ver.patch, ver.rc, ver.local)
+        };
+        assert_eq!(parse("6.6.52"), (6, 6, Some(52), None, None));
+        assert_eq!(parse("6.10"), (6, 10, None, None, None));
+        assert_eq!(parse("6.10.0-rc3"), (6, 10, Some(0), Some(3), None));
+        assert_eq!(
+            parse("6.6.52-zen1"),
+            (6, 6, Some(52), None, Some("zen1".into()))
+        );
+        assert_eq!(
+            parse("6.1.112-hardened1"),
+            (6, 1, Some(112), None, Some("hardened1".into()))
+        );
+        assert_eq!(
+            parse("6.6.52_xanmod1"),
+            (6, 6, Some(52), None, Some("xanmod1".into()))
+        );
+        assert_eq!(
+            parse("6.11.0-rc7-zen1"),
+            (6, 11, Some(0), Some(7), Some("zen1".into()))
+        );
+        for invalid in ["", "6", "6.", "v6.6.52", "6.6a", "lib"] {
+            assert!(invalid.parse::<KernelVersion>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn kernel_version_order() {
+        let ver = |val: &str| val.parse::<KernelVersion>().unwrap();
+        assert!(ver("6.10") > ver("6.9.12"));
+        assert!(ver("6.10.0-rc3") < ver("6.10.0"));
+        assert!(ver("6.10.0-rc3") < ver("6.10.0-rc10"));
+        assert!(ver("6.6.52") < ver("6.6.52-zen1"));
+        assert!(ver("6.6.1-zen2") < ver("6.6.1-zen10"));
+        assert!(ver("6.6.1-zen10") < ver("6.6.2-zen1"));
+        assert_eq!(ver("6.10"), ver("6.10.0"));
+    }
+
+    #[test]
+    fn newest_modules_dir() {
+        let td = tempfile::tempdir().unwrap();
+        let kernel = td.path();
+        assert!(KernelVersion::from_modules_dir(kernel).is_err());
+        for dir in ["6.6.50", "6.6.52-zen1", "6.6.9", "source"] {
+            std::fs::create_dir_all(kernel.join("lib/modules").join(dir)).unwrap();
+        }
+        let ver = KernelVersion::from_modules_dir(kernel).unwrap();
+        assert_eq!(ver.to_string(), "6.6.52-zen1");
+    }
+}
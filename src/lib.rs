@@ -1,4 +1,18 @@
+pub mod activation;
+pub mod channel;
+pub mod closure_diff;
 pub mod cmd;
+pub mod dry_activate;
+pub mod dry_build;
+pub mod elevate;
 pub mod flake;
+pub mod kernel_version;
 pub mod list_generations;
+pub mod nix_file;
+pub mod nix_log;
+pub mod nixos_version;
+pub mod profile;
+pub mod remote;
+pub mod repl;
 pub mod utils;
+pub mod vm;
@@ -1,23 +1,31 @@
-use chrono::{DateTime, Utc};
-use semver::Version;
+use chrono::{DateTime, Local, NaiveDate, Utc};
 use serde::{Serialize, Serializer};
 use serde_json::json;
 use std::{
     collections::BTreeMap,
+    fmt::Display,
     io::{self, ErrorKind},
     path::Path,
     process::Command,
 };
 
-const GEN_DIR: &str = "/nix/var/nix/profiles";
+use camino::Utf8Path;
+
+use crate::{
+    closure_diff::CURRENT_SYSTEM,
+    kernel_version::KernelVersion,
+    nixos_version::{NixosVersion, Support},
+    profile::Profile,
+};
+
+/// The system that was booted, which may differ from the running one after a switch
+pub const BOOTED_SYSTEM: &str = "/run/booted-system";
 
 #[derive(Debug, Serialize, Eq, PartialEq, Copy, Clone)]
+#[serde(transparent)]
 pub struct GenNumber {
-    #[serde(flatten)]
     pub num: u32,
 }
-#[derive(Debug, Serialize)]
-pub struct NixosVersion(pub String);
 
 impl PartialOrd for GenNumber {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
@@ -37,11 +45,13 @@ impl From<u32> for GenNumber {
     }
 }
 
-impl TryFrom<&Path> for GenNumber {
-    type Error = io::Error;
-
-    /// e.g. /nix/var/nix/profiles/system-14-link -> 14
-    fn try_from(gen_link: &Path) -> Result<Self, Self::Error> {
+impl GenNumber {
+    /// e.g. `/nix/var/nix/profiles/system-14-link` -> `("system", 14)`
+    ///
+    /// # Errors
+    ///
+    /// If the file name does not follow the `<profile-name>-N-link` format
+    pub fn parse_link(gen_link: &Path) -> io::Result<(&str, Self)> {
         let base = gen_link
             .file_name()
             .ok_or(io::Error::new(ErrorKind::NotFound, "invalid dir: `..`"))?
@@ -51,64 +61,260 @@ impl TryFrom<&Path> for GenNumber {
                 format!("Invalid Utf8 at {}", gen_link.display()),
             ))?;
 
-        if !base.starts_with("system-") || !base.ends_with("-link") {
+        let Some((name, num)) = base
+            .strip_suffix("-link")
+            .and_then(|b| b.rsplit_once('-'))
+            .filter(|(name, _)| !name.is_empty())
+        else {
             return Err(io::Error::new(
                 ErrorKind::InvalidInput,
                 format!(
-                    "file in {} must follow format 'system-X-link': {}",
+                    "file in {} must follow format '<profile-name>-X-link': {}",
                     gen_link.display(),
                     base
                 ),
             ));
-        }
-        let base_2 = base.trim_end_matches("-link");
-        let base_3 = base_2.trim_start_matches("system-");
-        let res = base_3.parse::<u32>().map_err(|e| {
+        };
+        let res = num.parse::<u32>().map_err(|e| {
             io::Error::new(
                 ErrorKind::InvalidInput,
                 format!("Failed conversion to u32: {}", e),
             )
         })?;
-        Ok(Self { num: res })
+        Ok((name, Self { num: res }))
+    }
+
+    /// As with [`GenNumber::parse_link`], but the link must belong to the named profile.
+    ///
+    /// # Errors
+    ///
+    /// If the link is not a `<profile_name>-N-link`
+    pub fn try_from_link(profile_name: &str, gen_link: &Path) -> io::Result<Self> {
+        match Self::parse_link(gen_link)? {
+            (name, num) if name == profile_name => Ok(num),
+            (name, _) => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} is a generation of profile `{}`, not `{}`",
+                    gen_link.display(),
+                    name,
+                    profile_name
+                ),
+            )),
+        }
     }
 }
 
+impl TryFrom<&Path> for GenNumber {
+    type Error = io::Error;
+
+    /// e.g. /nix/var/nix/profiles/system-14-link -> 14
+    fn try_from(gen_link: &Path) -> Result<Self, Self::Error> {
+        Self::parse_link(gen_link).map(|(_, num)| num)
+    }
+}
+
+/// A piece of a generation's metadata, or why it couldn't be read
+pub type MetaField<T> = Result<T, String>;
+
+/// Serialises a [`MetaField`] as its value, or `null` when it couldn't be read
+fn value_or_null<T: Serialize, S: Serializer>(
+    field: &MetaField<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    field.as_ref().ok().serialize(serializer)
+}
+
+/// What can be read of a generation. Each field is read on its own, so one that is missing, as
+/// with generations that predate `nixos-version --configuration-revision`, doesn't hide the rest.
 #[derive(Debug, Serialize)]
 pub struct GenerationMeta {
-    build_time: DateTime<Utc>,
-    nixos_version: NixosVersion,
-    kernel_version: Version,
-    cfg_revision: Option<String>,
-    specialisations: Vec<String>,
+    #[serde(serialize_with = "value_or_null")]
+    build_time: MetaField<DateTime<Utc>>,
+    #[serde(serialize_with = "value_or_null")]
+    nixos_version: MetaField<NixosVersion>,
+    #[serde(serialize_with = "value_or_null")]
+    kernel_version: MetaField<KernelVersion>,
+    #[serde(serialize_with = "value_or_null")]
+    cfg_revision: MetaField<Option<String>>,
+    #[serde(serialize_with = "value_or_null")]
+    specialisations: MetaField<Vec<String>>,
 }
 #[derive(Debug, Serialize)]
 pub struct NumberedGenMeta {
-    #[serde(flatten)]
+    #[serde(rename = "generation")]
     num: GenNumber,
     #[serde(flatten)]
     desc: GenerationMeta,
 }
 #[derive(Debug)]
 pub struct GenDescTable {
+    /// What the profile links to, i.e. what will be booted by default
     current: GenNumber,
+    /// What `/run/current-system` is. `None` when it isn't a generation of the profile.
+    running: Option<GenNumber>,
+    /// What `/run/booted-system` is. `None` when it isn't a generation of the profile.
+    booted: Option<GenNumber>,
     desc: BTreeMap<GenNumber, GenerationMeta>,
+    /// The day versions are judged end-of-life or stale on
+    today: NaiveDate,
 }
 impl Serialize for GenDescTable {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let mut ser_desc = serde_json::to_value(&self.desc).unwrap();
-        if let Some(entry) = ser_desc.get_mut(self.current.num.to_string()) {
-            // Step 3: Add `"current": true` to the selected entry
-            if let Some(obj) = entry.as_object_mut() {
-                obj.insert("current".to_string(), json!(true));
+        let mut ser_desc = serde_json::to_value(&self.desc).map_err(serde::ser::Error::custom)?;
+        for (key, num) in [
+            ("current", Some(self.current)),
+            ("running", self.running),
+            ("booted", self.booted),
+        ] {
+            // Add e.g. `"current": true` to the selected entry
+            if let Some(obj) = num
+                .and_then(|num| ser_desc.get_mut(num.num.to_string()))
+                .and_then(|entry| entry.as_object_mut())
+            {
+                obj.insert(key.to_string(), json!(true));
+            }
+        }
+        for (num, meta) in &self.desc {
+            // e.g. `"support": "end-of-life", "stale": true`, as of the table's day
+            if let (Ok(ver), Some(obj)) = (
+                &meta.nixos_version,
+                ser_desc
+                    .get_mut(num.num.to_string())
+                    .and_then(|entry| entry.get_mut("nixos_version"))
+                    .and_then(|ver| ver.as_object_mut()),
+            ) {
+                obj.insert("support".to_string(), json!(ver.support(self.today)));
+                obj.insert("stale".to_string(), json!(ver.is_stale(self.today)));
             }
         }
         ser_desc.serialize(serializer)
     }
 }
 
+impl GenDescTable {
+    /// Every generation of the profile, marking the one the profile points to as current, and
+    /// those that are running and were booted
+    ///
+    /// # Errors
+    ///
+    /// If the profile's directory can't be read, or its current generation can't be resolved
+    pub fn of_profile(profile: &Profile) -> io::Result<Self> {
+        let generation_of = |system: &str| {
+            profile
+                .generation_of(Utf8Path::new(system))
+                .unwrap_or_else(|e| {
+                    log::warn!("Could not resolve {}: {}", system, e);
+                    None
+                })
+        };
+        Ok(Self {
+            current: profile.current_generation()?,
+            running: generation_of(CURRENT_SYSTEM),
+            booted: generation_of(BOOTED_SYSTEM),
+            desc: GenerationMeta::run_cmd(profile)?.collect(),
+            today: Local::now().date_naive(),
+        })
+    }
+}
+
+/// As `nixos-rebuild list-generations` prints it: newest first, one row per generation. Fields
+/// that couldn't be read show as `Unknown`.
+///
+/// The alternate form (`{:#}`) follows the table with why each of those couldn't be read.
+impl Display for GenDescTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let header = [
+            "Generation",
+            "Build-date",
+            "NixOS version",
+            "Kernel",
+            "Configuration Revision",
+            "Specialisation",
+        ]
+        .map(String::from);
+        let rows = self.desc.iter().rev().map(|(num, meta)| {
+            let markers = [
+                (*num == self.current, " current"),
+                (Some(*num) == self.running, " running"),
+                (Some(*num) == self.booted, " booted"),
+            ];
+            let generation = markers
+                .into_iter()
+                .filter(|(marked, _)| *marked)
+                .fold(num.num.to_string(), |cell, (_, marker)| cell + marker);
+            fn cell<T>(field: &MetaField<T>, show: impl Fn(&T) -> String) -> String {
+                field.as_ref().map_or("Unknown".to_string(), show)
+            }
+            [
+                generation,
+                cell(&meta.build_time, |time| {
+                    time.with_timezone(&Local)
+                        .format("%Y-%m-%d %H:%M:%S")
+                        .to_string()
+                }),
+                cell(&meta.nixos_version, |ver| version_cell(ver, self.today)),
+                cell(&meta.kernel_version, KernelVersion::to_string),
+                cell(&meta.cfg_revision, |rev| rev.clone().unwrap_or_default()),
+                cell(&meta.specialisations, |specs| specs.join(" ")),
+            ]
+        });
+        let rows = std::iter::once(header).chain(rows).collect::<Vec<_>>();
+
+        let mut widths = [0; 6];
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+        for row in &rows {
+            let line = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:width$}", cell))
+                .collect::<Vec<_>>()
+                .join("  ");
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        for (system, num) in [
+            ("Running system", self.running),
+            ("Booted system", self.booted),
+        ] {
+            if num.is_none() {
+                writeln!(f, "{}: not in profile", system)?;
+            }
+        }
+        if f.alternate() {
+            for (num, meta) in self.desc.iter().rev() {
+                for (field, reason) in meta.unreadable() {
+                    writeln!(f, "Generation {}: no {}: {}", num.num, field, reason)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The version, flagged when the release is end-of-life or nixpkgs is stale:
+/// `23.11.20240101.abcdef0 (Tapir) [end-of-life, stale]`
+fn version_cell(ver: &NixosVersion, today: NaiveDate) -> String {
+    let flags = [
+        (ver.support(today) == Support::EndOfLife, "end-of-life"),
+        (ver.is_stale(today), "stale"),
+    ]
+    .into_iter()
+    .filter_map(|(flagged, flag)| flagged.then_some(flag))
+    .collect::<Vec<_>>();
+    if flags.is_empty() {
+        ver.to_string()
+    } else {
+        format!("{} [{}]", ver, flags.join(", "))
+    }
+}
+
 impl From<(u32, GenerationMeta)> for NumberedGenMeta {
     fn from(value: (u32, GenerationMeta)) -> Self {
         Self {
@@ -120,75 +326,104 @@ impl From<(u32, GenerationMeta)> for NumberedGenMeta {
 
 /// Takes a file path to a generations dir. Typically `/nix/var/nix/profiles/system-x-link`, but
 /// its canonicalised path can be used as well
-impl TryFrom<&Path> for GenerationMeta {
-    type Error = io::Error;
-
-    fn try_from(gen_dir: &Path) -> Result<Self, Self::Error> {
-        let _cannoned_dir = file_utils::CanonedStorePath::try_from(gen_dir)?;
-        let gen_number = GenNumber::try_from(gen_dir)?;
-        log::trace!("gen-number {}", gen_number.num);
-
-        let build_time = file_utils::creation_time(gen_dir)?;
-        log::trace!("creation-time {}", build_time);
-
-        let nixos_ver = Self::nixos_version(gen_dir)?;
-        log::trace!("nix-os version: {:?}", nixos_ver);
-
-        let parsed_kern_ver = Self::kernel_version(gen_dir)?;
-        log::trace!("kernel version: {}", parsed_kern_ver);
-
-        let mut cfg_command = Command::new(gen_dir.join("sw/bin/nixos-version"));
-        let cfg_command = cfg_command.arg("--configuration-revision");
-        let cfg_cmd_res = cfg_command.output()?.stdout;
-        let cfg_revision = if !cfg_cmd_res.is_empty() {
-            Some(String::from_utf8(cfg_cmd_res).unwrap())
-        } else {
-            None
-        };
+impl From<&Path> for GenerationMeta {
+    fn from(gen_dir: &Path) -> Self {
+        fn field<T>(gen_dir: &Path, name: &str, res: io::Result<T>) -> MetaField<T> {
+            res.map_err(|e| {
+                log::debug!("{}: no {}: {}", gen_dir.display(), name, e);
+                e.to_string()
+            })
+        }
 
-        let specialisations = std::fs::read_dir(gen_dir.join("specialisation"))?
-            // Filter out the error direntries
-            .filter_map(Result::ok)
-            // get the path
-            .map(|e| e.path())
-            // stringify
-            .map(|d| format!("{}", d.display()))
-            .collect();
-
-        Ok(GenerationMeta {
-            build_time,
-            nixos_version: NixosVersion(nixos_ver),
-            kernel_version: parsed_kern_ver,
-            cfg_revision,
-            specialisations,
-        })
+        GenerationMeta {
+            build_time: field(gen_dir, "build time", file_utils::creation_time(gen_dir)),
+            nixos_version: field(
+                gen_dir,
+                "NixOS version",
+                NixosVersion::of_generation(gen_dir),
+            ),
+            kernel_version: field(gen_dir, "kernel version", Self::kernel_version(gen_dir)),
+            cfg_revision: field(
+                gen_dir,
+                "configuration revision",
+                Self::cfg_revision(gen_dir),
+            ),
+            specialisations: field(gen_dir, "specialisations", Self::specialisations(gen_dir)),
+        }
     }
 }
 
 impl GenerationMeta {
     /// An iterator over (number, generation-meta) pairs. Usually `.collect::<_>()`ed into an
     /// ordered key/value data struct such as a `BTreeMap`.
-    pub fn run_cmd() -> io::Result<impl Iterator<Item = (GenNumber, Self)>> {
-        let gen_dir_root = Path::new(GEN_DIR);
+    pub fn run_cmd(profile: &Profile) -> io::Result<impl Iterator<Item = (GenNumber, Self)>> {
+        let profile_name = profile.name().to_string();
 
         // iterate over each entry in the directory...
-        let res = std::fs::read_dir(gen_dir_root)?
+        let res = std::fs::read_dir(profile.dir())?
             // for each path in the dir-entries iterator...
             .filter_map(|e| e.map(|e| e.path()).ok())
-            // keep only the ones that can map to a (number, path) pair
-            .filter_map(|e| GenNumber::try_from(e.as_path()).map(|num| (num, e)).ok())
-            // keep only the (number, path) pairs that can map to (number, generations-meta) pair
-            .filter_map(|(i, v)| GenerationMeta::try_from(v.as_path()).map(|v| (i, v)).ok());
+            // keep only the ones that can map to a (number, path) pair of this profile
+            .filter_map(move |e| {
+                GenNumber::try_from_link(&profile_name, e.as_path())
+                    .map(|num| (num, e))
+                    .ok()
+            })
+            // read what can be read of each
+            .map(|(i, v)| (i, GenerationMeta::from(v.as_path())));
         Ok(res)
     }
 
-    fn nixos_version(gen_dir: &Path) -> io::Result<String> {
-        let ver_dir = &gen_dir.join("nixos-version");
-        log::trace!("ver-dir: {}", ver_dir.display());
-        crate::utils::read_fst_line(ver_dir)
+    /// The fields that couldn't be read, and why
+    pub fn unreadable(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("build time", self.build_time.as_ref().err()),
+            ("NixOS version", self.nixos_version.as_ref().err()),
+            ("kernel version", self.kernel_version.as_ref().err()),
+            ("configuration revision", self.cfg_revision.as_ref().err()),
+            ("specialisations", self.specialisations.as_ref().err()),
+        ]
+        .into_iter()
+        .filter_map(|(field, reason)| Some((field, reason?.as_str())))
+        .collect()
+    }
+
+    /// `nixos-version --configuration-revision`, which is empty unless `system.configurationRevision`
+    /// is set
+    fn cfg_revision(gen_dir: &Path) -> io::Result<Option<String>> {
+        let out = Command::new(gen_dir.join("sw/bin/nixos-version"))
+            .arg("--configuration-revision")
+            .output()?;
+        if !out.status.success() {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            return Err(io::Error::other(format!(
+                "nixos-version failed: {}: {}",
+                out.status,
+                stderr.trim()
+            )));
+        }
+        let rev =
+            String::from_utf8(out.stdout).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        let rev = rev.trim();
+        Ok((!rev.is_empty()).then(|| rev.to_string()))
     }
 
-    fn kernel_version(gen_dir: &Path) -> io::Result<Version> {
+    /// Names of the generation's specialisations. Generations without any have no
+    /// `specialisation` dir.
+    fn specialisations(gen_dir: &Path) -> io::Result<Vec<String>> {
+        let entries = match std::fs::read_dir(gen_dir.join("specialisation")) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut specialisations = entries
+            .map(|e| e.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect::<io::Result<Vec<_>>>()?;
+        specialisations.sort();
+        Ok(specialisations)
+    }
+
+    fn kernel_version(gen_dir: &Path) -> io::Result<KernelVersion> {
         // canonicalise
         let mut kern_dir = std::fs::canonicalize(gen_dir.join("kernel"))?;
 
@@ -197,25 +432,13 @@ impl GenerationMeta {
             kern_dir = kern_dir.parent().unwrap().to_path_buf();
         }
 
-        // `lib/modules/<kernel-version/`
-        let kernel_ver_dir = std::fs::read_dir(kern_dir.join("lib/modules"))?
-            .next()
-            .ok_or(io::Error::new(
-                ErrorKind::NotFound,
-                "could not find semverdir",
-            ))??;
-
-        semver::Version::parse(&kernel_ver_dir.file_name().into_string().unwrap()).map_err(|e| {
-            io::Error::new(
-                ErrorKind::InvalidData,
-                format!("could not parse semver from dirname: {}", e),
-            )
-        })
+        // `lib/modules/<kernel-version>/`
+        KernelVersion::from_modules_dir(&kern_dir)
     }
 }
 
 // General file utilities
-mod file_utils {
+pub(crate) mod file_utils {
     use std::{
         ffi::OsStr,
         io,
@@ -233,7 +456,7 @@ mod file_utils {
     }
     /// <https://nix.dev/manual/nix/2.24/protocols/store-path#store-path-proper>
     /// `/nix/store/<digest>-<name>`
-    pub(super) struct CanonedStorePath {
+    pub(crate) struct CanonedStorePath {
         /// the 32-char string is the base32 encoding of the first 20bytes. We store the decoded
         /// bytes instead of the string.
         /// TODO: actually decode back to the 20 bytes...
@@ -252,47 +475,84 @@ mod file_utils {
             let fname = {
                 let fname = cannoned.file_name();
                 fname
-                    .ok_or(io::Error::new(
-                        io::ErrorKind::Other,
-                        "canonicalised to `..` for some reason",
-                    ))?
+                    .ok_or(io::Error::other("canonicalised to `..` for some reason"))?
                     .to_str()
-                    .ok_or(io::Error::new(
-                        io::ErrorKind::Other,
-                        "canonicalised to `..` for some reason",
-                    ))
+                    .ok_or(io::Error::other("canonicalised to `..` for some reason"))
             }?;
 
-            // verify the format
-            if fname.find('-') != Some(32) {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "expected <[char; 32]>-<name>. `-` not found at idx 32",
-                ));
-            }
-
-            // Now we can destructure the file name into digest, name, and filetype
-
+            let (digest, name) = split_digest(fname)?;
             let entry_type = if cannoned.is_dir() {
                 StoreEntryType::Directory
             } else {
-                match cannoned.extension().and_then(OsStr::to_str) {
-                    Some("drv") => StoreEntryType::ExtDrv,
-                    Some(_d) => StoreEntryType::ExtOther(()),
-                    None => StoreEntryType::ExtMissing,
-                }
+                StoreEntryType::from_extension(cannoned.extension())
             };
-
-            let (digest, name) = fname.split_at(32);
-            let name = name[1..].to_string();
             Ok(Self {
                 digest: digest.to_string(),
-                name,
+                name: name.to_string(),
                 _entry_type: entry_type,
             })
         }
     }
 
+    impl StoreEntryType {
+        fn from_extension(ext: Option<&OsStr>) -> Self {
+            match ext.and_then(OsStr::to_str) {
+                Some("drv") => Self::ExtDrv,
+                Some(_d) => Self::ExtOther(()),
+                None => Self::ExtMissing,
+            }
+        }
+    }
+
+    /// `<digest>-<name>` -> `(<digest>, <name>)`
+    fn split_digest(fname: &str) -> io::Result<(&str, &str)> {
+        // verify the format
+        if fname.find('-') != Some(32) {
+            return Err(io::Error::other(
+                "expected <[char; 32]>-<name>. `-` not found at idx 32",
+            ));
+        }
+        let (digest, name) = fname.split_at(32);
+        Ok((digest, &name[1..]))
+    }
+
+    impl CanonedStorePath {
+        /// As with `TryFrom<&Path>`, but for a path that may not be on this machine, such as one
+        /// listed by `nix-store -qR` on a remote. The filesystem is not consulted: the entry type
+        /// is judged by extension alone.
+        pub(crate) fn from_store_path(path: &str) -> io::Result<Self> {
+            let path = Path::new(path);
+            let fname = path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .ok_or_else(|| io::Error::other(format!("not a store path: {}", path.display())))?;
+            let (digest, name) = split_digest(fname)?;
+            Ok(Self {
+                digest: digest.to_string(),
+                name: name.to_string(),
+                _entry_type: StoreEntryType::from_extension(path.extension()),
+            })
+        }
+
+        /// Splits the name into package name and version, as nix does: the version starts at the
+        /// first `-` not followed by a letter.
+        ///
+        /// `firefox-131.0.2` -> `("firefox", Some("131.0.2"))`, `etc` -> `("etc", None)`
+        pub(crate) fn pname_version(&self) -> (&str, Option<&str>) {
+            self.name
+                .match_indices('-')
+                .find(|(idx, _)| {
+                    self.name[idx + 1..]
+                        .chars()
+                        .next()
+                        .is_some_and(|c| !c.is_alphabetic())
+                })
+                .map_or((self.name.as_str(), None), |(idx, _)| {
+                    (&self.name[..idx], Some(&self.name[idx + 1..]))
+                })
+        }
+    }
+
     impl From<&CanonedStorePath> for PathBuf {
         fn from(value: &CanonedStorePath) -> Self {
             PathBuf::from(format!("/nix/store/{}-{}", value.digest, value.name))
@@ -305,3 +565,203 @@ mod file_utils {
             .map(DateTime::<Utc>::from)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gen_number_parse_link() {
+        let parse =
+            |s: &str| GenNumber::parse_link(Path::new(s)).map(|(n, g)| (n.to_string(), g.num));
+        assert_eq!(
+            parse("/nix/var/nix/profiles/system-14-link").unwrap(),
+            ("system".to_string(), 14)
+        );
+        assert_eq!(
+            parse("/nix/var/nix/profiles/system-profiles/lab-bench-3-link").unwrap(),
+            ("lab-bench".to_string(), 3)
+        );
+        assert!(parse("/nix/var/nix/profiles/system").is_err());
+        assert!(parse("/nix/var/nix/profiles/system-profiles").is_err());
+        assert!(parse("/nix/var/nix/profiles/system-x-link").is_err());
+        assert!(parse("/nix/var/nix/profiles/-3-link").is_err());
+
+        let lab_3 = Path::new("/nix/var/nix/profiles/system-profiles/lab-3-link");
+        assert_eq!(GenNumber::try_from_link("lab", lab_3).unwrap().num, 3);
+        assert!(GenNumber::try_from_link("system", lab_3).is_err());
+    }
+
+    #[test]
+    fn gen_desc_table() {
+        let build_time = "2024-10-01T09:30:00Z".parse::<DateTime<Utc>>().unwrap();
+        let meta = |nixos: &str, kernel: &str, rev: Option<&str>, specs: &[&str]| GenerationMeta {
+            build_time: Ok(build_time),
+            nixos_version: Ok(nixos.parse().unwrap()),
+            kernel_version: Ok(kernel.parse().unwrap()),
+            cfg_revision: Ok(rev.map(String::from)),
+            specialisations: Ok(specs.iter().map(|s| s.to_string()).collect()),
+        };
+        let table = GenDescTable {
+            current: 41.into(),
+            running: Some(42.into()),
+            booted: Some(41.into()),
+            desc: BTreeMap::from([
+                (
+                    41.into(),
+                    meta("24.05.20240915.a1b2c3d (Uakari)", "6.6.50", None, &[]),
+                ),
+                (
+                    42.into(),
+                    meta(
+                        "24.05.20241001.e4f5a6b (Uakari)",
+                        "6.6.52",
+                        Some("0f3c9e1"),
+                        &["gaming", "work"],
+                    ),
+                ),
+            ]),
+            today: NaiveDate::from_ymd_opt(2025, 2, 1).unwrap(),
+        };
+
+        let date = build_time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S");
+        assert_eq!(
+            table.to_string(),
+            format!(
+                "\
+Generation         Build-date           NixOS version                                         Kernel  Configuration Revision  Specialisation
+42 running         {date}  24.05.20241001.e4f5a6b (Uakari) [end-of-life, stale]  6.6.52  0f3c9e1                 gaming work
+41 current booted  {date}  24.05.20240915.a1b2c3d (Uakari) [end-of-life, stale]  6.6.50
+"
+            )
+        );
+
+        let json = serde_json::to_value(&table).unwrap();
+        assert_eq!(json["41"]["current"], true);
+        assert_eq!(json["41"]["booted"], true);
+        assert_eq!(json["42"]["running"], true);
+        assert!(json["42"].get("current").is_none());
+        assert_eq!(json["41"]["nixos_version"]["support"], "end-of-life");
+        assert_eq!(json["41"]["nixos_version"]["stale"], true);
+
+        let ver = "24.05.20241001.e4f5a6b (Uakari)".parse().unwrap();
+        let today = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        assert_eq!(
+            version_cell(&ver, today(2024, 11, 1)),
+            "24.05.20241001.e4f5a6b (Uakari)"
+        );
+        assert_eq!(
+            version_cell(&ver, today(2025, 2, 1)),
+            "24.05.20241001.e4f5a6b (Uakari) [end-of-life, stale]"
+        );
+
+        // after a `test` activation
+        let table = GenDescTable {
+            running: None,
+            ..table
+        };
+        assert!(table
+            .to_string()
+            .ends_with("Running system: not in profile\n"));
+        assert_eq!(json["42"]["specialisations"], json!(["gaming", "work"]));
+    }
+
+    #[test]
+    fn partial_generation_meta() {
+        let td = tempfile::tempdir().unwrap();
+        let gen_dir = td.path();
+        std::fs::write(
+            gen_dir.join("nixos-version"),
+            "21.11.337975.eabc3821918 (Porcupine)\n",
+        )
+        .unwrap();
+
+        let meta = GenerationMeta::from(gen_dir);
+        assert_eq!(
+            meta.nixos_version.as_ref().unwrap().to_string(),
+            "21.11.337975.eabc3821918 (Porcupine)"
+        );
+        assert_eq!(meta.specialisations, Ok(vec![]));
+        assert!(meta.kernel_version.is_err());
+        assert!(meta.cfg_revision.is_err());
+        let unreadable = meta
+            .unreadable()
+            .into_iter()
+            .map(|(field, _)| field)
+            .collect::<Vec<_>>();
+        assert!(unreadable.ends_with(&["kernel version", "configuration revision"]));
+
+        let json = serde_json::to_value(&meta).unwrap();
+        assert!(json["kernel_version"].is_null());
+        assert_eq!(json["specialisations"], json!([]));
+
+        let table = GenDescTable {
+            current: 3.into(),
+            running: Some(3.into()),
+            booted: Some(3.into()),
+            desc: BTreeMap::from([(3.into(), meta)]),
+            today: NaiveDate::from_ymd_opt(2025, 2, 1).unwrap(),
+        };
+        let row = table.to_string().lines().nth(1).unwrap().to_string();
+        assert!(row.starts_with("3 current running booted"));
+        assert!(row.contains("Unknown"));
+        assert!(!table
+            .to_string()
+            .contains("Generation 3: no kernel version: "));
+        assert!(format!("{:#}", table).contains("Generation 3: no kernel version: "));
+    }
+
+    #[test]
+    fn cfg_revision_failures() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let td = tempfile::tempdir().unwrap();
+        let gen_dir = td.path();
+        let bin = gen_dir.join("sw/bin");
+        std::fs::create_dir_all(&bin).unwrap();
+        let set_script = |body: &str| {
+            let script = bin.join("nixos-version");
+            std::fs::write(&script, format!("#!/bin/sh\n{}\n", body)).unwrap();
+            std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        };
+
+        set_script("echo ' 0123abc '");
+        assert_eq!(
+            GenerationMeta::cfg_revision(gen_dir).unwrap().as_deref(),
+            Some("0123abc")
+        );
+        set_script("true");
+        assert_eq!(GenerationMeta::cfg_revision(gen_dir).unwrap(), None);
+
+        set_script("echo 'unknown option' >&2; exit 1");
+        let err = GenerationMeta::cfg_revision(gen_dir).unwrap_err();
+        assert!(err.to_string().contains("unknown option"));
+        set_script(r"printf '\377\376'");
+        let err = GenerationMeta::cfg_revision(gen_dir).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn pname_version() {
+        let split = |name: &str| {
+            let path = format!("/nix/store/{}-{}", "a".repeat(32), name);
+            let store_path = file_utils::CanonedStorePath::from_store_path(&path).unwrap();
+            let (pname, version) = store_path.pname_version();
+            (pname.to_string(), version.map(String::from))
+        };
+        assert_eq!(
+            split("firefox-131.0.2"),
+            ("firefox".into(), Some("131.0.2".into()))
+        );
+        assert_eq!(
+            split("xdg-desktop-portal-gtk-1.15.1"),
+            ("xdg-desktop-portal-gtk".into(), Some("1.15.1".into()))
+        );
+        assert_eq!(split("etc"), ("etc".into(), None));
+        assert_eq!(
+            split("unit-sshd.service"),
+            ("unit-sshd.service".into(), None)
+        );
+        assert!(file_utils::CanonedStorePath::from_store_path("/nix/store/short-name").is_err());
+    }
+}
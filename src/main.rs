@@ -21,7 +21,7 @@
 use std::{
     collections::BTreeMap,
     error::Error,
-    io::{self, BufRead, BufReader, Write},
+    io::{self, BufRead, BufReader, IsTerminal, Write},
     path::{Path, PathBuf},
     process::{Command as CliCommand, Output, Stdio},
     string::ToString,
@@ -32,6 +32,7 @@ use clap::Parser;
 use cmd::{AllArgs, Cli, SubCommand};
 use list_generations::GenerationMeta;
 use nixos_rsbuild::{
+    activation::ActivationFailed,
     cmd::{self, BuildSubComms, UtilSubCommand},
     list_generations,
 };
@@ -40,31 +41,27 @@ use tempdir::TempDir;
 fn main() -> Result<(), Box<dyn Error>> {
     let cli = initial_init()?;
 
-    match cli {
-        // We only have list-generations util running for now
-        SubCommand::Util {
-            task: UtilSubCommand::ListGenerations { json },
-        } => {
-            let gens_iter = GenerationMeta::run_cmd()?;
-            println!("{:#?}", gens_iter.collect::<BTreeMap<_, _>>());
-            Ok(())
-        }
+    let res = match cli {
         // I've only tried out build, test, switch, boot.
-        SubCommand::Builders { task, arg } => Ok(task.run_build(arg)?),
-        SubCommand::Util { task } => unimplemented!("todo: implement {:?}", task),
+        SubCommand::Builders { task, arg } => task.run_build(arg),
+        SubCommand::Util { task } => task.run_util(),
+    };
+
+    // A failed activation exits with the code of switch-to-configuration, as nixos-rebuild does
+    if let Err(e) = &res {
+        if let Some(failed) = ActivationFailed::from_io(e) {
+            log::error!("{}", failed);
+            std::process::exit(failed.code.unwrap_or(1));
+        }
     }
+    Ok(res?)
 }
 
 /// Sanatises arg[0]
-/// Ensures not run as root
 /// Initialises logger
 /// Parses cli, returning the subcommand of the result
+/// Ensures not run as root interactively, unless explicitly allowed
 fn initial_init() -> Result<SubCommand, Box<dyn Error>> {
-    if nix::unistd::Uid::current().is_root() {
-        // TODO: this pre-empts automation. something to think about
-        return Err("This program should not be run as root!".into());
-    };
-
     // sanatise executable name
     let args = std::env::args();
     let mut args = args.peekable();
@@ -73,7 +70,7 @@ fn initial_init() -> Result<SubCommand, Box<dyn Error>> {
     };
     if !fst.ends_with("nixos-rsbuild") {
         return Err("Cli args did not begin with a path to file named 'nixos-rsbuild'".into());
-    };
+    }
 
     // initialise logger
     env_logger::Builder::new()
@@ -97,5 +94,16 @@ fn initial_init() -> Result<SubCommand, Box<dyn Error>> {
         // mut_cli.command.try_init_to_default_flake();
         cli
     };
+
+    // Automation (timers, provisioning scripts, containers) runs as root without a terminal. At
+    // a terminal, root is more likely a habitual `sudo` in front.
+    if nix::unistd::Uid::current().is_root() {
+        if !cli.allow_root && io::stdin().is_terminal() {
+            return Err(
+                "This program should not be run as root! Pass --allow-root to do so anyway".into(),
+            );
+        }
+        log::info!("Running as root: privilege escalation is skipped");
+    }
     Ok(cli.command)
 }
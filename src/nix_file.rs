@@ -0,0 +1,254 @@
+use std::{fmt::Display, io, process::Command};
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{cmd::BuildSubComms, nix_log, repl};
+
+/// The entry-point NixOS uses to build a `configuration.nix`
+const NIXOS_ENTRY: &str = "<nixpkgs/nixos>";
+
+/// Non-flake counterpart to [`crate::flake::FlakeRef`]: `nix-build <file> -A <attr>`
+#[derive(Debug, Clone)]
+pub struct NixFileRef {
+    /// Path to a nix file, or a search-path lookup such as `<nixpkgs/nixos>`
+    pub file: String,
+    /// Attribute path to build out of `file`
+    pub attr: String,
+    /// Set as `NIXOS_CONFIG` for the build. `None` leaves it up to nix to find the configuration.
+    pub nixos_config: Option<Utf8PathBuf>,
+}
+
+impl Display for NixFileRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -A {}", self.file, self.attr)
+    }
+}
+
+impl NixFileRef {
+    /// # `--file` and/or `--attr`
+    /// - builds `[<attr>.]config.system.build.<output>` out of the file
+    /// - `--attr` without `--file` builds out of `./default.nix`
+    ///
+    /// # Neither
+    /// - builds `<nixpkgs/nixos>`. Its `toplevel` is exposed as `system`.
+    /// - the configuration is read from `$NIXOS_CONFIG`, or `nixos-config` in the search-path
+    ///   (`-I`/`$NIX_PATH`), falling back to `/etc/nixos/configuration.nix`
+    ///
+    /// # Error
+    /// - `--attr` without `--file`, and no `./default.nix` present
+    /// - building `<nixpkgs/nixos>`, and no configuration could be found
+    pub fn init(
+        file: Option<&Utf8Path>,
+        attr: Option<&str>,
+        task: &BuildSubComms,
+        include: &[String],
+    ) -> io::Result<Self> {
+        let output = task.build_output();
+
+        if file.is_none() && attr.is_none() {
+            let attr = match output {
+                "toplevel" => "system",
+                other => other,
+            };
+            return Ok(Self {
+                file: NIXOS_ENTRY.to_string(),
+                attr: attr.to_string(),
+                nixos_config: Self::default_nixos_config(include)?,
+            });
+        }
+
+        let file = match file {
+            Some(file) => file.to_path_buf(),
+            None => {
+                let default = Utf8PathBuf::from("default.nix");
+                if !default.is_file() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "--attr without --file builds from `./default.nix`, which does not exist",
+                    ));
+                }
+                default
+            }
+        };
+        let attr = attr
+            .into_iter()
+            .chain(["config", "system", "build", output])
+            .collect::<Vec<_>>()
+            .join(".");
+
+        Ok(Self {
+            file: file.to_string(),
+            attr,
+            nixos_config: None,
+        })
+    }
+
+    /// Only `/etc/nixos/configuration.nix` needs to be set explicitly: `$NIXOS_CONFIG` is
+    /// inherited by the build, and nix resolves `nixos-config` in the search-path on its own.
+    fn default_nixos_config(include: &[String]) -> io::Result<Option<Utf8PathBuf>> {
+        if std::env::var_os("NIXOS_CONFIG").is_some() || Self::search_path_config(include).is_some()
+        {
+            return Ok(None);
+        }
+
+        let default = Utf8PathBuf::from(crate::utils::DEFAULT_CONFIGURATION_NIX);
+        if !default.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "No configuration to build: set $NIXOS_CONFIG, `-I nixos-config=<path>`, or create {}",
+                    default
+                ),
+            ));
+        }
+        Ok(Some(default))
+    }
+
+    /// `nixos-config=<path>` in the search-path: `-I` first, then `$NIX_PATH`
+    fn search_path_config(include: &[String]) -> Option<Utf8PathBuf> {
+        let nix_path = std::env::var("NIX_PATH").unwrap_or_default();
+        include
+            .iter()
+            .map(String::as_str)
+            .chain(nix_path.split(':'))
+            .find_map(|entry| entry.strip_prefix("nixos-config="))
+            .map(Utf8PathBuf::from)
+    }
+
+    /// The file defining the configuration, for editing: `--file` when given, otherwise
+    /// `$NIXOS_CONFIG`, `nixos-config` in the search-path, or `/etc/nixos/configuration.nix`.
+    pub fn config_file(file: Option<&Utf8Path>, include: &[String]) -> Utf8PathBuf {
+        file.map(Utf8Path::to_path_buf)
+            .or_else(|| std::env::var("NIXOS_CONFIG").ok().map(Utf8PathBuf::from))
+            .or_else(|| Self::search_path_config(include))
+            .unwrap_or_else(|| Utf8PathBuf::from(crate::utils::DEFAULT_CONFIGURATION_NIX))
+    }
+
+    /// The configuration [`Self::init`] would build out of, for `nix repl`. `--file` is imported,
+    /// and called when it is a function.
+    ///
+    /// # Error
+    /// - as with [`Self::init`]
+    pub fn host_repl(
+        file: Option<&Utf8Path>,
+        attr: Option<&str>,
+        include: &[String],
+    ) -> io::Result<repl::HostRepl> {
+        if file.is_none() && attr.is_none() {
+            return Ok(repl::HostRepl {
+                host: format!("import {} {{ }}", NIXOS_ENTRY),
+                nixos_config: Self::default_nixos_config(include)?,
+            });
+        }
+
+        let file = match file {
+            Some(file) => file.canonicalize_utf8()?,
+            None => Utf8PathBuf::from("default.nix")
+                .canonicalize_utf8()
+                .map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        "--attr without --file uses `./default.nix`, which does not exist",
+                    )
+                })?,
+        };
+        let attr_path = attr.map(|attr| attr.split('.').collect::<Vec<_>>());
+        Ok(repl::HostRepl {
+            host: format!(
+                "(let f = import {}; in if builtins.isFunction f then f {{ }} else f){}",
+                file,
+                repl::attr_selector(attr_path.as_deref().unwrap_or_default())
+            ),
+            nixos_config: None,
+        })
+    }
+
+    /// `nix-build`, rendering its progress. `print_build_logs` shows the build logs in full.
+    pub fn run_nix_build(
+        &self,
+        out_dir: &Utf8Path,
+        extra_flags: &[String],
+        print_build_logs: bool,
+    ) -> io::Result<()> {
+        log::info!("Building in non-flake mode: {}", self);
+
+        let mut cmd = Command::new("nix-build");
+        cmd.arg(&self.file)
+            .args(["-A", &self.attr])
+            .arg("--out-link")
+            .arg(out_dir.join("result"))
+            .args(extra_flags);
+        if let Some(cfg) = &self.nixos_config {
+            cmd.env("NIXOS_CONFIG", cfg);
+        }
+
+        nix_log::run_with_progress(&mut cmd, print_build_logs)
+            .map_err(|e| io::Error::new(e.kind(), format!("nix-build of {} failed: {}", self, e)))
+    }
+
+    /// `nix-build --dry-run`, returning what it reports on stderr
+    pub fn dry_run(&self, extra_flags: &[String]) -> io::Result<String> {
+        log::info!("Dry-running build in non-flake mode: {}", self);
+
+        let mut cmd = Command::new("nix-build");
+        cmd.arg(&self.file)
+            .args(["-A", &self.attr])
+            .arg("--dry-run")
+            .args(extra_flags);
+        if let Some(cfg) = &self.nixos_config {
+            cmd.env("NIXOS_CONFIG", cfg);
+        }
+        crate::utils::stderr_output(&mut cmd)
+    }
+
+    /// Evaluates the derivation without building it: `nix-instantiate <file> -A <attr>`
+    pub fn instantiate(&self, extra_flags: &[String]) -> io::Result<Utf8PathBuf> {
+        log::info!("Evaluating derivation in non-flake mode: {}", self);
+
+        let mut cmd = Command::new("nix-instantiate");
+        cmd.arg(&self.file)
+            .args(["-A", &self.attr])
+            .args(extra_flags);
+        if let Some(cfg) = &self.nixos_config {
+            cmd.env("NIXOS_CONFIG", cfg);
+        }
+        let drv = crate::utils::output(&mut cmd)?;
+        Ok(Utf8PathBuf::from(drv.trim()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn init_attr() {
+        let file = Utf8Path::new("/etc/nixos/machines.nix");
+
+        let data = NixFileRef::init(Some(file), Some("lab"), &BuildSubComms::Switch, &[]).unwrap();
+        assert_eq!(
+            data.to_string(),
+            "/etc/nixos/machines.nix -A lab.config.system.build.toplevel"
+        );
+        assert!(data.nixos_config.is_none());
+
+        let data = NixFileRef::init(Some(file), None, &BuildSubComms::BuildVm, &[]).unwrap();
+        assert_eq!(
+            data.to_string(),
+            "/etc/nixos/machines.nix -A config.system.build.vm"
+        );
+    }
+
+    #[test]
+    fn init_nixos_entry() {
+        let include = ["nixos-config=/srv/nixos/configuration.nix".to_string()];
+
+        let data = NixFileRef::init(None, None, &BuildSubComms::Boot, &include).unwrap();
+        assert_eq!(data.to_string(), "<nixpkgs/nixos> -A system");
+        assert!(data.nixos_config.is_none());
+
+        let data =
+            NixFileRef::init(None, None, &BuildSubComms::BuildVmWithBootloader, &include).unwrap();
+        assert_eq!(data.to_string(), "<nixpkgs/nixos> -A vmWithBootLoader");
+    }
+}
@@ -0,0 +1,546 @@
+//! Nix's `--log-format internal-json`: each line of stderr is either `@nix <json>`, describing an
+//! activity (a build, a download, ...) starting, stopping or reporting a result, or plain text.
+//!
+//! [`LogEvent`] parses a single line, [`BuildProgress`] folds the events into a summary, and
+//! [`ProgressDisplay`] renders both as they come in.
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, IsTerminal, Write},
+    process::{Command, Stdio},
+};
+
+use serde::Deserialize;
+
+use crate::utils::human_bytes;
+
+/// Marks a line as an event, rather than plain text
+const EVENT_PREFIX: &str = "@nix ";
+
+/// Messages up to and including this level are shown: error, warn, notice, info
+const MAX_MSG_LEVEL: u8 = 3;
+
+/// Nix's `ActivityType`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActivityType {
+    CopyPath,
+    FileTransfer,
+    Realise,
+    CopyPaths,
+    Builds,
+    Build,
+    OptimiseStore,
+    VerifyPaths,
+    Substitute,
+    QueryPathInfo,
+    PostBuildHook,
+    BuildWaiting,
+    FetchTree,
+    Unknown(u64),
+}
+
+impl From<u64> for ActivityType {
+    fn from(num: u64) -> Self {
+        match num {
+            100 => Self::CopyPath,
+            101 => Self::FileTransfer,
+            102 => Self::Realise,
+            103 => Self::CopyPaths,
+            104 => Self::Builds,
+            105 => Self::Build,
+            106 => Self::OptimiseStore,
+            107 => Self::VerifyPaths,
+            108 => Self::Substitute,
+            109 => Self::QueryPathInfo,
+            110 => Self::PostBuildHook,
+            111 => Self::BuildWaiting,
+            112 => Self::FetchTree,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+/// What an activity reports while running. Nix's `ResultType`, with its fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ActivityResult {
+    BuildLogLine(String),
+    PostBuildLogLine(String),
+    SetPhase(String),
+    Progress {
+        done: u64,
+        expected: u64,
+        running: u64,
+        failed: u64,
+    },
+    SetExpected {
+        activity: ActivityType,
+        expected: u64,
+    },
+    /// Results that aren't rendered: linked files, untrusted/corrupted paths, fetch status
+    Other(u64),
+}
+
+/// A single line of nix's stderr
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogEvent {
+    Start {
+        id: u64,
+        activity: ActivityType,
+        text: String,
+        parent: u64,
+        /// For a build, the derivation being built
+        drv: Option<String>,
+    },
+    Stop {
+        id: u64,
+    },
+    Result {
+        id: u64,
+        result: ActivityResult,
+    },
+    Msg {
+        level: u8,
+        msg: String,
+    },
+    /// Anything not prefixed with `@nix `
+    Plain(String),
+}
+
+/// The JSON, as nix writes it
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum RawEvent {
+    Start {
+        id: u64,
+        #[serde(rename = "type")]
+        kind: u64,
+        #[serde(default)]
+        text: String,
+        #[serde(default)]
+        parent: u64,
+        #[serde(default)]
+        fields: Vec<Field>,
+    },
+    Stop {
+        id: u64,
+    },
+    Result {
+        id: u64,
+        #[serde(rename = "type")]
+        kind: u64,
+        #[serde(default)]
+        fields: Vec<Field>,
+    },
+    Msg {
+        level: u8,
+        msg: String,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Field {
+    Int(u64),
+    Str(String),
+}
+
+impl Field {
+    fn int(fields: &[Self], idx: usize) -> u64 {
+        match fields.get(idx) {
+            Some(Self::Int(num)) => *num,
+            _ => 0,
+        }
+    }
+
+    fn string(fields: &[Self], idx: usize) -> String {
+        match fields.get(idx) {
+            Some(Self::Str(s)) => s.clone(),
+            _ => String::new(),
+        }
+    }
+}
+
+impl LogEvent {
+    /// # Errors
+    ///
+    /// If a line prefixed with `@nix ` is not a recognised event
+    pub fn parse(line: &str) -> serde_json::Result<Self> {
+        let Some(json) = line.strip_prefix(EVENT_PREFIX) else {
+            return Ok(Self::Plain(line.to_string()));
+        };
+
+        Ok(match serde_json::from_str(json)? {
+            RawEvent::Start {
+                id,
+                kind,
+                text,
+                parent,
+                fields,
+            } => {
+                let activity = ActivityType::from(kind);
+                let drv = (activity == ActivityType::Build).then(|| Field::string(&fields, 0));
+                Self::Start {
+                    id,
+                    activity,
+                    text,
+                    parent,
+                    drv,
+                }
+            }
+            RawEvent::Stop { id } => Self::Stop { id },
+            RawEvent::Result { id, kind, fields } => {
+                let result = match kind {
+                    101 => ActivityResult::BuildLogLine(Field::string(&fields, 0)),
+                    104 => ActivityResult::SetPhase(Field::string(&fields, 0)),
+                    105 => ActivityResult::Progress {
+                        done: Field::int(&fields, 0),
+                        expected: Field::int(&fields, 1),
+                        running: Field::int(&fields, 2),
+                        failed: Field::int(&fields, 3),
+                    },
+                    106 => ActivityResult::SetExpected {
+                        activity: Field::int(&fields, 0).into(),
+                        expected: Field::int(&fields, 1),
+                    },
+                    107 => ActivityResult::PostBuildLogLine(Field::string(&fields, 0)),
+                    other => ActivityResult::Other(other),
+                };
+                Self::Result { id, result }
+            }
+            RawEvent::Msg { level, msg } => Self::Msg { level, msg },
+        })
+    }
+}
+
+/// `/nix/store/<hash>-hello-2.12.1.drv` -> `hello-2.12.1`
+fn drv_name(drv: &str) -> &str {
+    let name = drv.rsplit('/').next().unwrap_or(drv);
+    let name = name.split_once('-').map_or(name, |(_hash, name)| name);
+    name.strip_suffix(".drv").unwrap_or(name)
+}
+
+#[derive(Debug)]
+struct Activity {
+    kind: ActivityType,
+    /// Derivation name, for builds
+    name: String,
+    phase: Option<String>,
+    /// Latest progress. Bytes, for transfers.
+    done: u64,
+}
+
+/// Running totals over an activity stream
+#[derive(Debug, Default)]
+pub struct BuildProgress {
+    activities: HashMap<u64, Activity>,
+    pub builds_done: u64,
+    pub builds_expected: u64,
+    pub builds_running: u64,
+    pub builds_failed: u64,
+    /// Bytes of downloads which have finished
+    downloaded_finished: u64,
+}
+
+impl BuildProgress {
+    pub fn update(&mut self, event: &LogEvent) {
+        match event {
+            LogEvent::Start {
+                id, activity, drv, ..
+            } => {
+                let name = drv.as_deref().map(drv_name).unwrap_or_default().to_string();
+                self.activities.insert(
+                    *id,
+                    Activity {
+                        kind: *activity,
+                        name,
+                        phase: None,
+                        done: 0,
+                    },
+                );
+            }
+            LogEvent::Stop { id } => {
+                if let Some(act) = self.activities.remove(id) {
+                    if act.kind == ActivityType::FileTransfer {
+                        self.downloaded_finished += act.done;
+                    }
+                }
+            }
+            LogEvent::Result { id, result } => {
+                let Some(act) = self.activities.get_mut(id) else {
+                    return;
+                };
+                match result {
+                    ActivityResult::SetPhase(phase) => act.phase = Some(phase.clone()),
+                    ActivityResult::Progress {
+                        done,
+                        expected,
+                        running,
+                        failed,
+                    } => {
+                        act.done = *done;
+                        if act.kind == ActivityType::Builds {
+                            self.builds_done = *done;
+                            self.builds_expected = *expected;
+                            self.builds_running = *running;
+                            self.builds_failed = *failed;
+                        }
+                    }
+                    ActivityResult::SetExpected {
+                        activity: ActivityType::Build,
+                        expected,
+                    } if act.kind == ActivityType::Builds => self.builds_expected = *expected,
+                    _ => {}
+                }
+            }
+            LogEvent::Msg { .. } | LogEvent::Plain(_) => {}
+        }
+    }
+
+    /// Bytes downloaded so far, including those of downloads still running
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded_finished
+            + self
+                .activities
+                .values()
+                .filter(|act| act.kind == ActivityType::FileTransfer)
+                .map(|act| act.done)
+                .sum::<u64>()
+    }
+
+    /// The most recently started build still running, and its phase
+    pub fn current_build(&self) -> Option<(&str, Option<&str>)> {
+        self.activities
+            .iter()
+            .filter(|(_, act)| act.kind == ActivityType::Build)
+            .max_by_key(|(id, _)| **id)
+            .map(|(_, act)| (act.name.as_str(), act.phase.as_deref()))
+    }
+
+    /// Name of the derivation an activity builds
+    fn build_name(&self, id: u64) -> Option<&str> {
+        self.activities
+            .get(&id)
+            .filter(|act| act.kind == ActivityType::Build)
+            .map(|act| act.name.as_str())
+    }
+
+    /// One-line summary, such as `[1/3 built, 1 running, 4.2 MiB downloaded] building hello-2.12.1
+    /// (buildPhase)`
+    pub fn summary(&self) -> String {
+        let mut summary = format!("[{}/{} built", self.builds_done, self.builds_expected);
+        if self.builds_running > 0 {
+            summary += &format!(", {} running", self.builds_running);
+        }
+        if self.builds_failed > 0 {
+            summary += &format!(", {} failed", self.builds_failed);
+        }
+        summary += &format!(", {} downloaded]", human_bytes(self.downloaded()));
+
+        match self.current_build() {
+            Some((name, Some(phase))) => summary + &format!(" building {} ({})", name, phase),
+            Some((name, None)) => summary + &format!(" building {}", name),
+            None => summary,
+        }
+    }
+}
+
+/// Renders the activity stream: messages and plain lines as they come, build logs with
+/// `--print-build-logs`, and, when `live`, a status line kept at the bottom.
+pub struct ProgressDisplay<W: Write> {
+    out: W,
+    print_build_logs: bool,
+    live: bool,
+    progress: BuildProgress,
+}
+
+impl<W: Write> ProgressDisplay<W> {
+    pub fn new(out: W, print_build_logs: bool, live: bool) -> Self {
+        Self {
+            out,
+            print_build_logs,
+            live,
+            progress: BuildProgress::default(),
+        }
+    }
+
+    pub fn progress(&self) -> &BuildProgress {
+        &self.progress
+    }
+
+    /// # Errors
+    ///
+    /// If writing out failed
+    pub fn handle(&mut self, line: &str) -> io::Result<()> {
+        let event = LogEvent::parse(line).unwrap_or_else(|e| {
+            log::debug!("unrecognised nix log event ({}): {}", e, line);
+            LogEvent::Plain(line.to_string())
+        });
+
+        let shown = match &event {
+            LogEvent::Plain(text) => Some(text.clone()),
+            LogEvent::Msg { level, msg } if *level <= MAX_MSG_LEVEL => Some(msg.clone()),
+            LogEvent::Result {
+                id,
+                result: ActivityResult::BuildLogLine(text) | ActivityResult::PostBuildLogLine(text),
+            } if self.print_build_logs => {
+                let name = self.progress.build_name(*id).unwrap_or("build");
+                Some(format!("{}> {}", name, text))
+            }
+            _ => None,
+        };
+        self.progress.update(&event);
+
+        if let Some(text) = shown {
+            self.clear_status()?;
+            writeln!(self.out, "{}", text)?;
+        }
+        if self.live {
+            write!(self.out, "\r\x1b[K{}", self.progress.summary())?;
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the status line with the final summary
+    ///
+    /// # Errors
+    ///
+    /// If writing out failed
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.clear_status()?;
+        writeln!(self.out, "{}", self.progress.summary())
+    }
+
+    fn clear_status(&mut self) -> io::Result<()> {
+        if self.live {
+            write!(self.out, "\r\x1b[K")?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs a nix command with `--log-format internal-json`, rendering its stderr with a
+/// [`ProgressDisplay`]. The status line is only drawn on a terminal.
+///
+/// # Errors
+///
+/// If the command could not be run, or did not succeed
+pub fn run_with_progress(cmd: &mut Command, print_build_logs: bool) -> io::Result<()> {
+    cmd.args(["--log-format", "internal-json"])
+        .stderr(Stdio::piped());
+    log::trace!("running: {:?}", cmd);
+    let mut child = cmd.spawn()?;
+
+    let stderr = io::stderr();
+    let live = stderr.is_terminal();
+    let mut display = ProgressDisplay::new(stderr.lock(), print_build_logs, live);
+    if let Some(nix_err) = child.stderr.take() {
+        for line in BufReader::new(nix_err).lines() {
+            display.handle(&line?)?;
+        }
+    }
+    display.finish()?;
+
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{:?} failed: {}",
+            cmd.get_program(),
+            status
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUILD_HELLO: &str = include_str!("../tests/fixtures/nix-build-hello.log");
+
+    fn replay(log: &str, lines: usize, print_build_logs: bool) -> (BuildProgress, String) {
+        let mut out = Vec::new();
+        let mut display = ProgressDisplay::new(&mut out, print_build_logs, false);
+        for line in log.lines().take(lines) {
+            display.handle(line).unwrap();
+        }
+        let progress = std::mem::take(&mut display.progress);
+        (progress, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn parse_events() {
+        let start = LogEvent::parse(
+            r#"@nix {"action":"start","fields":["/nix/store/0c6a-hello-2.12.1.drv","",1,1],"id":7,"level":3,"parent":0,"text":"building '/nix/store/0c6a-hello-2.12.1.drv'","type":105}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            start,
+            LogEvent::Start {
+                id: 7,
+                activity: ActivityType::Build,
+                text: "building '/nix/store/0c6a-hello-2.12.1.drv'".to_string(),
+                parent: 0,
+                drv: Some("/nix/store/0c6a-hello-2.12.1.drv".to_string()),
+            }
+        );
+
+        let progress =
+            LogEvent::parse(r#"@nix {"action":"result","fields":[1,3,1,0],"id":2,"type":105}"#)
+                .unwrap();
+        assert_eq!(
+            progress,
+            LogEvent::Result {
+                id: 2,
+                result: ActivityResult::Progress {
+                    done: 1,
+                    expected: 3,
+                    running: 1,
+                    failed: 0
+                }
+            }
+        );
+
+        assert_eq!(
+            LogEvent::parse("warning: Git tree is dirty").unwrap(),
+            LogEvent::Plain("warning: Git tree is dirty".to_string())
+        );
+        assert!(LogEvent::parse(r#"@nix {"action":"explode"}"#).is_err());
+    }
+
+    #[test]
+    fn progress_mid_build() {
+        let (progress, _) = replay(BUILD_HELLO, 16, false);
+        assert_eq!(progress.builds_done, 0);
+        assert_eq!(progress.builds_expected, 2);
+        assert_eq!(
+            progress.current_build(),
+            Some(("hello-2.12.1", Some("buildPhase")))
+        );
+        assert_eq!(progress.downloaded(), 2_097_152);
+        assert_eq!(
+            progress.summary(),
+            "[0/2 built, 1 running, 2.0 MiB downloaded] building hello-2.12.1 (buildPhase)"
+        );
+    }
+
+    #[test]
+    fn progress_finished() {
+        let (progress, out) = replay(BUILD_HELLO, usize::MAX, false);
+        assert_eq!(progress.builds_done, 2);
+        assert_eq!(progress.current_build(), None);
+        assert_eq!(progress.downloaded(), 3_145_728);
+        assert_eq!(progress.summary(), "[2/2 built, 3.0 MiB downloaded]");
+        // talkative messages and build logs are left out
+        assert_eq!(
+            out,
+            "warning: Git tree '/etc/nixos' is dirty\nthese 2 derivations will be built:\n"
+        );
+    }
+
+    #[test]
+    fn print_build_logs() {
+        let (_, out) = replay(BUILD_HELLO, usize::MAX, true);
+        assert!(out.contains("hello-2.12.1> unpacking source archive\n"));
+        assert!(out.contains("nixos-system-lab-24.11> building the system configuration...\n"));
+    }
+}
@@ -0,0 +1,317 @@
+//! NixOS versions, as `nixos-version` reports them: `24.05.20240601.abcdef0 (Uakari)`, or
+//! `24.11pre691017.b833ff01a0d6 (Vicuna)` for unstable. Older releases carry a revision count in
+//! place of the date: `21.11.337975.eabc3821918 (Porcupine)`.
+use std::{fmt::Display, io, path::Path, process::Command, str::FromStr};
+
+use chrono::NaiveDate;
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+
+/// A nixpkgs older than this, by its build date, is reported as stale
+pub const STALE_AFTER_DAYS: i64 = 90;
+
+/// Each stable release, and the last day it receives updates. Releases older than the first
+/// entry are all end-of-life.
+const RELEASE_SUPPORT: &[(&str, &str)] = &[
+    ("20.03", "2020-10-31"),
+    ("20.09", "2021-04-30"),
+    ("21.05", "2021-12-31"),
+    ("21.11", "2022-06-30"),
+    ("22.05", "2022-12-31"),
+    ("22.11", "2023-06-30"),
+    ("23.05", "2023-12-31"),
+    ("23.11", "2024-06-30"),
+    ("24.05", "2024-12-31"),
+    ("24.11", "2025-06-30"),
+    ("25.05", "2025-12-31"),
+    ("25.11", "2026-06-30"),
+    ("26.05", "2026-12-31"),
+];
+
+/// Whether a release still receives updates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, strum::Display)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum Support {
+    Supported,
+    EndOfLife,
+    /// A pre-release, i.e. `nixos-unstable`. How current it is is down to its build date.
+    Unstable,
+    /// Newer than the releases nixos-rsbuild knows of
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NixosVersion {
+    /// e.g. `24.05`
+    pub release: String,
+    /// Built from `nixos-unstable`, ahead of `release`: marked `pre`, or built before the
+    /// release's month
+    pub pre_release: bool,
+    /// The date of the nixpkgs commit. Absent in versions carrying a revision count instead.
+    pub build_date: Option<NaiveDate>,
+    /// Abbreviated nixpkgs commit
+    pub revision: Option<String>,
+    /// e.g. `Uakari`
+    pub codename: Option<String>,
+    /// As reported, for display
+    raw: String,
+}
+
+impl NixosVersion {
+    /// Reads a generation's version, with `nixos-version --json` where the generation has it,
+    /// falling back to its `nixos-version` file.
+    ///
+    /// # Errors
+    ///
+    /// If neither could be read, or the version could not be parsed
+    pub fn of_generation(gen_dir: &Path) -> io::Result<Self> {
+        let from_json = Command::new(gen_dir.join("sw/bin/nixos-version"))
+            .arg("--json")
+            .output()
+            .ok()
+            .filter(|out| out.status.success())
+            .and_then(|out| Self::from_json(&out.stdout));
+        if let Some(ver) = from_json {
+            return Ok(ver);
+        }
+
+        let ver_file = gen_dir.join("nixos-version");
+        log::trace!("ver-file: {}", ver_file.display());
+        crate::utils::read_fst_line(&ver_file)?
+            .trim_end()
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// `{"nixosVersion": ..., "nixpkgsRevision": ...}`, as printed by `nixos-version --json`
+    fn from_json(json: &[u8]) -> Option<Self> {
+        let json = serde_json::from_slice::<serde_json::Value>(json).ok()?;
+        let mut ver = json.get("nixosVersion")?.as_str()?.parse::<Self>().ok()?;
+        if ver.revision.is_none() {
+            ver.revision = json
+                .get("nixpkgsRevision")
+                .and_then(serde_json::Value::as_str)
+                .map(|rev| rev.chars().take(7).collect());
+        }
+        Some(ver)
+    }
+
+    /// The last day the release receives updates, when it is a known stable release
+    pub fn end_of_life(&self) -> Option<NaiveDate> {
+        RELEASE_SUPPORT
+            .iter()
+            .find(|(release, _)| *release == self.release)
+            .and_then(|(_, eol)| NaiveDate::parse_from_str(eol, "%Y-%m-%d").ok())
+    }
+
+    /// The release's support status, as of `today`
+    ///
+    /// ```
+    /// use chrono::NaiveDate;
+    /// use nixos_rsbuild::nixos_version::{NixosVersion, Support};
+    /// let ver = "23.11.20240101.abcdef0 (Tapir)".parse::<NixosVersion>().unwrap();
+    /// let today = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+    /// assert_eq!(ver.support(today), Support::Supported);
+    /// let today = NaiveDate::from_ymd_opt(2024, 7, 1).unwrap();
+    /// assert_eq!(ver.support(today), Support::EndOfLife);
+    /// ```
+    pub fn support(&self, today: NaiveDate) -> Support {
+        if self.pre_release {
+            return Support::Unstable;
+        }
+        match self.end_of_life() {
+            Some(eol) if today > eol => Support::EndOfLife,
+            Some(_) => Support::Supported,
+            None if RELEASE_SUPPORT
+                .first()
+                .is_some_and(|(oldest, _)| self.release.as_str() < *oldest) =>
+            {
+                Support::EndOfLife
+            }
+            None => Support::Unknown,
+        }
+    }
+
+    /// Whether nixpkgs was over [`STALE_AFTER_DAYS`] old as of `today`. Unknown build dates are
+    /// not stale.
+    pub fn is_stale(&self, today: NaiveDate) -> bool {
+        self.build_date
+            .is_some_and(|date| (today - date).num_days() > STALE_AFTER_DAYS)
+    }
+}
+
+/// Splits off a leading run of digits: `05.2024` -> `("05", ".2024")`
+fn leading_digits(val: &str) -> Option<(&str, &str)> {
+    let end = val.find(|c: char| !c.is_ascii_digit()).unwrap_or(val.len());
+    (end > 0).then(|| val.split_at(end))
+}
+
+impl FromStr for NixosVersion {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected <YY>.<MM>[pre]... [(<codename>)], got `{}`", val);
+
+        let (version, codename) = match val.trim().split_once(" (") {
+            Some((version, codename)) => (
+                version,
+                Some(codename.strip_suffix(')').ok_or_else(invalid)?.to_string()),
+            ),
+            None => (val.trim(), None),
+        };
+
+        let (year, rest) = leading_digits(version).ok_or_else(invalid)?;
+        let (month, rest) = rest
+            .strip_prefix('.')
+            .and_then(leading_digits)
+            .ok_or_else(invalid)?;
+        let release = format!("{}.{}", year, month);
+
+        let (pre_release, rest) = match rest.strip_prefix("pre") {
+            Some(rest) => (true, rest),
+            None => (false, rest.strip_prefix('.').unwrap_or(rest)),
+        };
+
+        // `<date or revision count>.<revision>`, either of which may be missing, as with
+        // `24.11pre-git`
+        let mut build_date = None;
+        let mut revision = None;
+        for part in rest.split('.').filter(|part| !part.is_empty()).take(2) {
+            if part.bytes().all(|b| b.is_ascii_digit()) {
+                if part.len() == 8 {
+                    build_date = NaiveDate::parse_from_str(part, "%Y%m%d").ok();
+                }
+            } else if part.bytes().all(|b| b.is_ascii_hexdigit()) {
+                revision = Some(part.to_string());
+            }
+        }
+
+        // Unstable builds may carry the upcoming release's number without `pre`
+        let release_month =
+            NaiveDate::parse_from_str(&format!("20{}.{}.01", year, month), "%Y.%m.%d");
+        let pre_release = pre_release
+            || build_date
+                .zip(release_month.ok())
+                .is_some_and(|(built, released)| built < released);
+
+        Ok(Self {
+            release,
+            pre_release,
+            build_date,
+            revision,
+            codename,
+            raw: val.trim().to_string(),
+        })
+    }
+}
+
+impl Display for NixosVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+/// The version's parts. Its support status and staleness depend on the day, so are left to the
+/// caller, who knows which day it reports on.
+impl Serialize for NixosVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut ser = serializer.serialize_struct("NixosVersion", 6)?;
+        ser.serialize_field("version", &self.raw)?;
+        ser.serialize_field("release", &self.release)?;
+        ser.serialize_field("pre_release", &self.pre_release)?;
+        ser.serialize_field("build_date", &self.build_date)?;
+        ser.serialize_field("revision", &self.revision)?;
+        ser.serialize_field("codename", &self.codename)?;
+        ser.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_nixos_versions() {
+        let ver = "24.05.20240601.abcdef0 (Uakari)"
+            .parse::<NixosVersion>()
+            .unwrap();
+        assert_eq!(ver.release, "24.05");
+        assert!(!ver.pre_release);
+        assert_eq!(ver.build_date, NaiveDate::from_ymd_opt(2024, 6, 1));
+        assert_eq!(ver.revision.as_deref(), Some("abcdef0"));
+        assert_eq!(ver.codename.as_deref(), Some("Uakari"));
+        assert_eq!(ver.to_string(), "24.05.20240601.abcdef0 (Uakari)");
+
+        let ver = "24.11pre691017.b833ff01a0d6 (Vicuna)"
+            .parse::<NixosVersion>()
+            .unwrap();
+        assert_eq!(ver.release, "24.11");
+        assert!(ver.pre_release);
+        assert_eq!(ver.build_date, None);
+        assert_eq!(ver.revision.as_deref(), Some("b833ff01a0d6"));
+
+        let ver = "21.11.337975.eabc3821918 (Porcupine)"
+            .parse::<NixosVersion>()
+            .unwrap();
+        assert_eq!(ver.build_date, None);
+        assert_eq!(ver.revision.as_deref(), Some("eabc3821918"));
+
+        let ver = "24.11pre-git".parse::<NixosVersion>().unwrap();
+        assert!(ver.pre_release);
+        assert_eq!(
+            (ver.build_date, ver.revision, ver.codename),
+            (None, None, None)
+        );
+
+        assert!("unstable".parse::<NixosVersion>().is_err());
+        assert!("24.05 (Uakari".parse::<NixosVersion>().is_err());
+    }
+
+    #[test]
+    fn support_status() {
+        let today = NaiveDate::from_ymd_opt(2025, 3, 1).unwrap();
+        let support = |val: &str| val.parse::<NixosVersion>().unwrap().support(today);
+        assert_eq!(
+            support("24.11.20250220.0b1f2e3 (Vicuna)"),
+            Support::Supported
+        );
+        assert_eq!(
+            support("24.05.20241220.0b1f2e3 (Uakari)"),
+            Support::EndOfLife
+        );
+        assert_eq!(support("19.09.2477.350b6ec (Loris)"), Support::EndOfLife);
+        assert_eq!(
+            support("25.05pre755230.3a7e5b1 (Warbler)"),
+            Support::Unstable
+        );
+        assert_eq!(support("38.05.20380501.0b1f2e3"), Support::Unknown);
+        // Numbered after the upcoming release, but built before it
+        let ver = "25.05.20250101.abcdef0".parse::<NixosVersion>().unwrap();
+        assert!(ver.pre_release);
+        assert_eq!(ver.support(today), Support::Unstable);
+        assert!(
+            !"25.05.20250501.abcdef0"
+                .parse::<NixosVersion>()
+                .unwrap()
+                .pre_release
+        );
+
+        let stale = |val: &str| val.parse::<NixosVersion>().unwrap().is_stale(today);
+        assert!(stale("24.11.20241120.0b1f2e3 (Vicuna)"));
+        assert!(!stale("24.11.20250220.0b1f2e3 (Vicuna)"));
+        assert!(!stale("21.11.337975.eabc3821918 (Porcupine)"));
+    }
+
+    #[test]
+    fn version_json() {
+        let json = br#"{"nixosVersion":"24.11.20250220.0b1f2e3 (Vicuna)","nixpkgsRevision":"0b1f2e3d4c5b6a798877665544332211aabbccdd"}"#;
+        let ver = NixosVersion::from_json(json).unwrap();
+        assert_eq!(ver.release, "24.11");
+        assert_eq!(ver.revision.as_deref(), Some("0b1f2e3"));
+
+        let json = br#"{"nixosVersion":"24.11pre-git","nixpkgsRevision":"0b1f2e3d4c5b6a798877665544332211aabbccdd"}"#;
+        let ver = NixosVersion::from_json(json).unwrap();
+        assert_eq!(ver.revision.as_deref(), Some("0b1f2e3"));
+        assert!(NixosVersion::from_json(b"not json").is_none());
+    }
+}
@@ -0,0 +1,322 @@
+use std::{fmt::Display, io, path::Path};
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{list_generations::GenNumber, remote::Target, utils};
+
+/// The profile `nixos-rebuild` manages by default. Each `system-N-link` alongside it is a
+/// generation.
+pub const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+/// Where the profiles selected with `--profile-name` live.
+pub const SYSTEM_PROFILES_DIR: &str = "/nix/var/nix/profiles/system-profiles";
+
+/// A nix profile: a sym-link to the current generation, e.g. `/nix/var/nix/profiles/system`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    path: Utf8PathBuf,
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path)
+    }
+}
+
+impl From<Utf8PathBuf> for Profile {
+    fn from(path: Utf8PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl Profile {
+    /// `/nix/var/nix/profiles/system`
+    pub fn system() -> Self {
+        Self {
+            path: Utf8PathBuf::from(SYSTEM_PROFILE),
+        }
+    }
+
+    /// `system` -> `/nix/var/nix/profiles/system`
+    /// `<name>` -> `/nix/var/nix/profiles/system-profiles/<name>`
+    /// ```
+    /// use nixos_rsbuild::profile::Profile;
+    /// assert_eq!(Profile::named("system"), Profile::system());
+    /// assert_eq!(
+    ///     Profile::named("lab").to_string(),
+    ///     "/nix/var/nix/profiles/system-profiles/lab"
+    /// );
+    /// ```
+    pub fn named(name: &str) -> Self {
+        if name == "system" {
+            return Self::system();
+        }
+        Self {
+            path: Utf8Path::new(SYSTEM_PROFILES_DIR).join(name),
+        }
+    }
+
+    pub fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+
+    /// The profiles name, which prefixes each of its generation links: `<name>-N-link`
+    pub fn name(&self) -> &str {
+        self.path.file_name().unwrap_or_default()
+    }
+
+    /// The directory holding the profile, along with its generation links
+    pub fn dir(&self) -> &Utf8Path {
+        self.path.parent().unwrap_or(Utf8Path::new("/"))
+    }
+
+    /// Registers the built configuration as a new generation of this profile. Analogous to
+    /// `nix-env -p <profile> --set <toplevel>`, and what creates the next `system-N-link`.
+    ///
+    /// # Errors
+    ///
+    /// If `nix-env` could not be run, or reports a failure.
+    pub fn set_generation(&self, target: &Target, toplevel: &Utf8Path) -> io::Result<()> {
+        log::info!("Registering {} as a new generation of {}", toplevel, self);
+        // A named profile's dir doesn't exist until its first generation
+        let mkdir = ["mkdir", "-p", "-m", "0755", self.dir().as_str()];
+        utils::run(&mut target.elevated_command(&mkdir)?).map_err(|e| {
+            io::Error::other(format!(
+                "Failed to create the profile dir {}: {}",
+                self.dir(),
+                e
+            ))
+        })?;
+        let argv = [
+            "nix-env",
+            "-p",
+            self.path.as_str(),
+            "--set",
+            toplevel.as_str(),
+        ];
+        utils::run(&mut target.elevated_command(&argv)?).map_err(|e| {
+            io::Error::other(format!(
+                "Failed to register {} in profile {}: {}",
+                toplevel, self, e
+            ))
+        })
+    }
+
+    /// The generation the profile currently links to.
+    ///
+    /// # Errors
+    ///
+    /// If the profile is not a sym-link to a `<profile>-N-link`
+    pub fn current_generation(&self) -> io::Result<GenNumber> {
+        let gen_link = std::fs::read_link(&self.path).map_err(|e| {
+            io::Error::new(e.kind(), format!("Could not read profile {}: {}", self, e))
+        })?;
+        GenNumber::try_from_link(self.name(), &gen_link)
+    }
+
+    /// The latest generation that predates the current one, i.e. what a rollback will activate.
+    ///
+    /// # Errors
+    ///
+    /// If the current generation can't be resolved, or there is no earlier generation
+    pub fn previous_generation(&self, target: &Target) -> io::Result<GenNumber> {
+        let gen_link = target.read_link(&self.path).map_err(|e| {
+            io::Error::new(e.kind(), format!("Could not read profile {}: {}", self, e))
+        })?;
+        let current = GenNumber::try_from_link(self.name(), gen_link.as_std_path())?;
+
+        // Only the link names are needed: no metadata is read, so every generation counts
+        target
+            .read_dir_names(self.dir())?
+            .iter()
+            .filter_map(|name| GenNumber::try_from_link(self.name(), Path::new(name)).ok())
+            .filter(|num| *num < current)
+            .max()
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No generation prior to {} in {}", current.num, self),
+            ))
+    }
+
+    /// `/nix/var/nix/profiles/system` -> `/nix/var/nix/profiles/system-N-link`
+    /// ```
+    /// use nixos_rsbuild::profile::Profile;
+    /// let link = Profile::named("lab").generation_link(3.into());
+    /// assert_eq!(link, "/nix/var/nix/profiles/system-profiles/lab-3-link");
+    /// ```
+    pub fn generation_link(&self, num: GenNumber) -> Utf8PathBuf {
+        Utf8PathBuf::from(format!("{}-{}-link", self.path, num.num))
+    }
+
+    /// The generation whose system `system` resolves to, e.g. `/run/current-system`. `None` when
+    /// it isn't a generation of this profile, such as after a `test` activation.
+    ///
+    /// # Errors
+    ///
+    /// If `system` or the profile's directory can't be read
+    pub fn generation_of(&self, system: &Utf8Path) -> io::Result<Option<GenNumber>> {
+        let toplevel = system.canonicalize_utf8()?;
+        for entry in self.dir().read_dir_utf8()? {
+            let link = entry?.into_path();
+            let Ok(num) = GenNumber::try_from_link(self.name(), link.as_std_path()) else {
+                continue;
+            };
+            if link.canonicalize_utf8().is_ok_and(|gen| gen == toplevel) {
+                return Ok(Some(num));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Points the profile back to its previous generation. Analogous to
+    /// `nix-env --rollback -p <profile>`.
+    ///
+    /// # Errors
+    ///
+    /// If `nix-env` could not be run, or reports a failure, e.g. there is nothing to roll back to.
+    pub fn rollback(&self, target: &Target) -> io::Result<()> {
+        log::info!("Rolling back {} to its previous generation", self);
+        let argv = ["nix-env", "--rollback", "-p", self.path.as_str()];
+        utils::run(&mut target.elevated_command(&argv)?)
+            .map_err(|e| io::Error::other(format!("Failed to roll back profile {}: {}", self, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        elevate::Elevate,
+        remote::test_support::{remote_commands, shim_target},
+    };
+
+    #[test]
+    fn set_generation() {
+        let td = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(td.path()).unwrap();
+        let profile = Profile::from(dir.join("system"));
+        let toplevel = Utf8Path::new("/nix/store/aaa-nixos-system-lab");
+
+        // No nix-env to register with here: the failure is reported, rather than activating an
+        // unregistered generation
+        let err = profile
+            .set_generation(&shim_target(dir, Elevate::None), toplevel)
+            .unwrap_err();
+        assert!(
+            err.to_string().starts_with(&format!(
+                "Failed to register {} in profile {}",
+                toplevel, profile
+            )),
+            "{}",
+            err
+        );
+        assert_eq!(
+            remote_commands(dir),
+            [
+                format!("mkdir -p -m 0755 {}", dir),
+                format!("nix-env -p {} --set {}", profile, toplevel)
+            ]
+        );
+    }
+
+    #[test]
+    fn set_generation_creates_profile_dir() {
+        let td = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(td.path()).unwrap();
+        let profiles_dir = dir.join("system-profiles");
+        let profile = Profile::from(profiles_dir.join("lab"));
+        let toplevel = Utf8Path::new("/nix/store/aaa-nixos-system-lab");
+
+        assert!(profile
+            .set_generation(&shim_target(dir, Elevate::None), toplevel)
+            .is_err());
+        assert!(profiles_dir.is_dir());
+        assert_eq!(
+            remote_commands(dir),
+            [
+                format!("mkdir -p -m 0755 {}", profiles_dir),
+                format!("nix-env -p {} --set {}", profile, toplevel)
+            ]
+        );
+    }
+
+    #[test]
+    fn current_generation() {
+        let td = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(td.path()).unwrap();
+        let profile = Profile::from(dir.join("lab"));
+        assert_eq!(profile.name(), "lab");
+        assert_eq!(profile.dir(), dir);
+        assert!(profile.current_generation().is_err());
+
+        std::os::unix::fs::symlink("lab-7-link", profile.path()).unwrap();
+        assert_eq!(profile.current_generation().unwrap(), 7.into());
+
+        // a link to another profiles generation is not ours
+        let other = Profile::from(dir.join("other"));
+        std::os::unix::fs::symlink("lab-7-link", other.path()).unwrap();
+        assert!(other.current_generation().is_err());
+    }
+
+    #[test]
+    fn previous_generation() {
+        let td = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(td.path()).unwrap();
+        let profile = Profile::from(dir.join("system"));
+        // None of these have a `specialisation` dir, or anything else to read metadata from
+        for num in [1, 2, 4, 7] {
+            let system = dir.join(format!("store/{}-nixos-system", num));
+            std::fs::create_dir_all(&system).unwrap();
+            std::os::unix::fs::symlink(&system, profile.generation_link(num.into())).unwrap();
+        }
+        std::os::unix::fs::symlink("system-7-link", profile.path()).unwrap();
+
+        let local = Target::local(Elevate::None);
+        assert_eq!(profile.previous_generation(&local).unwrap(), 4.into());
+        let remote = shim_target(dir, Elevate::None);
+        assert_eq!(profile.previous_generation(&remote).unwrap(), 4.into());
+
+        std::fs::remove_file(profile.path()).unwrap();
+        std::os::unix::fs::symlink("system-1-link", profile.path()).unwrap();
+        assert!(profile.previous_generation(&local).is_err());
+    }
+
+    #[test]
+    fn generation_of() {
+        let td = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(td.path()).unwrap();
+        for system in [
+            "store/aaa-nixos-system",
+            "store/bbb-nixos-system",
+            "store/ccc-test",
+        ] {
+            std::fs::create_dir_all(dir.join(system)).unwrap();
+        }
+        let profiles = dir.join("profiles");
+        std::fs::create_dir(&profiles).unwrap();
+        let profile = Profile::from(profiles.join("system"));
+        std::os::unix::fs::symlink(
+            dir.join("store/aaa-nixos-system"),
+            profiles.join("system-1-link"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(
+            dir.join("store/bbb-nixos-system"),
+            profiles.join("system-2-link"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("system-2-link", profile.path()).unwrap();
+
+        let running = dir.join("current-system");
+        std::os::unix::fs::symlink(dir.join("store/aaa-nixos-system"), &running).unwrap();
+        assert_eq!(profile.generation_of(&running).unwrap(), Some(1.into()));
+        assert_eq!(
+            profile.generation_of(profile.path()).unwrap(),
+            Some(2.into())
+        );
+        assert_eq!(
+            profile.generation_of(&dir.join("store/ccc-test")).unwrap(),
+            None
+        );
+        assert!(profile.generation_of(&dir.join("booted-system")).is_err());
+    }
+}
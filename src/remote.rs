@@ -0,0 +1,608 @@
+use std::{
+    cell::OnceCell,
+    ffi::OsString,
+    io,
+    process::{Command, Stdio},
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use tempfile::TempDir;
+
+use crate::{
+    elevate::{Elevate, AUTO_PROGRAMS},
+    utils::{output, run},
+};
+
+/// A machine reached over ssh, e.g. with `--target-host user@host`
+///
+/// All commands share a single control connection, which is closed once this is dropped.
+#[derive(Debug)]
+pub struct SshHost {
+    /// `[user@]host`
+    host: String,
+    /// The `ssh` executable
+    ssh: OsString,
+    /// `$NIX_SSHOPTS`, followed by the connection-sharing options
+    opts: Vec<String>,
+    /// Holds the control socket. Only kept around to be cleaned up on drop.
+    _control_dir: TempDir,
+    /// Whether the remote user is root. Queried on first use.
+    is_root: OnceCell<bool>,
+}
+
+impl SshHost {
+    /// Sets up connection sharing to `[user@]host`. Connects on first use.
+    ///
+    /// # Errors
+    ///
+    /// If the directory for the control socket could not be created
+    pub fn connect(host: &str) -> io::Result<Self> {
+        Self::with_ssh("ssh", host)
+    }
+
+    fn with_ssh(ssh: impl Into<OsString>, host: &str) -> io::Result<Self> {
+        let control_dir = tempfile::Builder::new()
+            .prefix("nixrsbuild-ssh-")
+            .tempdir()?;
+        let mut opts = std::env::var("NIX_SSHOPTS")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        opts.extend([
+            "-o".to_string(),
+            "ControlMaster=auto".to_string(),
+            "-o".to_string(),
+            format!("ControlPath={}/ssh-%n", control_dir.path().display()),
+            "-o".to_string(),
+            "ControlPersist=60".to_string(),
+        ]);
+        Ok(Self {
+            host: host.to_string(),
+            ssh: ssh.into(),
+            opts,
+            _control_dir: control_dir,
+            is_root: OnceCell::new(),
+        })
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The store url used by `nix copy`: `ssh://[user@]host`
+    pub fn store_uri(&self) -> String {
+        format!("ssh://{}", self.host)
+    }
+
+    /// `NIX_SSHOPTS` to hand to nix, so that its ssh connections go through ours
+    pub fn nix_sshopts(&self) -> String {
+        self.opts.join(" ")
+    }
+
+    /// `ssh <opts> <host> -- <argv>`. ssh hands the remote shell a single command line, so each
+    /// argument gets quoted.
+    pub fn command(&self, argv: &[&str]) -> Command {
+        let remote_cmd = argv
+            .iter()
+            .map(|arg| shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut cmd = Command::new(&self.ssh);
+        cmd.args(&self.opts)
+            .arg(&self.host)
+            .arg("--")
+            .arg(remote_cmd);
+        cmd
+    }
+
+    /// Builds a derivation that has already been copied over, returning its output path.
+    /// Analogous to `nix-store --realise <drv>` on the remote machine.
+    ///
+    /// # Errors
+    ///
+    /// If the build fails, or does not report an output path
+    pub fn realise(&self, drv: &Utf8Path) -> io::Result<Utf8PathBuf> {
+        log::info!("Building {} on {}", drv, self.host);
+        let out = output(&mut self.command(&["nix-store", "--realise", drv.as_str()]))?;
+        out.lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .map(|line| Utf8PathBuf::from(line.trim()))
+            .ok_or(io::Error::other(format!(
+                "Building {} on {} reported no output path",
+                drv, self.host
+            )))
+    }
+
+    fn is_root(&self) -> io::Result<bool> {
+        if let Some(is_root) = self.is_root.get() {
+            return Ok(*is_root);
+        }
+        let is_root = output(&mut self.command(&["id", "-u"]))?.trim() == "0";
+        Ok(*self.is_root.get_or_init(|| is_root))
+    }
+}
+
+impl Drop for SshHost {
+    /// Closes the shared connection, if it was ever opened
+    fn drop(&mut self) {
+        log::trace!("Closing ssh control connection to {}", self.host);
+        let _ = Command::new(&self.ssh)
+            .args(&self.opts)
+            .args(["-O", "exit", &self.host])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+/// The machine a configuration gets deployed to: this one, or `--target-host`. Also carries how
+/// root privileges are obtained there.
+#[derive(Debug)]
+pub struct Target {
+    /// `None` for this machine
+    remote: Option<SshHost>,
+    elevate: Elevate,
+    /// What [`Elevate::Auto`] settled on, once looked for
+    auto_program: OnceCell<&'static str>,
+}
+
+impl Target {
+    /// This machine
+    pub fn local(elevate: Elevate) -> Self {
+        Self {
+            remote: None,
+            elevate,
+            auto_program: OnceCell::new(),
+        }
+    }
+
+    /// `--target-host`, if set.
+    ///
+    /// # Errors
+    ///
+    /// If the connection to the remote host could not be set up
+    pub fn init(target_host: Option<&str>, elevate: Elevate) -> io::Result<Self> {
+        Ok(Self {
+            remote: target_host.map(SshHost::connect).transpose()?,
+            elevate,
+            auto_program: OnceCell::new(),
+        })
+    }
+
+    pub fn remote(&self) -> Option<&SshHost> {
+        self.remote.as_ref()
+    }
+
+    /// `argv` to run on the target
+    pub fn command(&self, argv: &[&str]) -> Command {
+        match &self.remote {
+            None => {
+                let mut cmd = Command::new(argv[0]);
+                cmd.args(&argv[1..]);
+                cmd
+            }
+            Some(host) => host.command(argv),
+        }
+    }
+
+    fn is_root(&self) -> io::Result<bool> {
+        match &self.remote {
+            None => Ok(nix::unistd::Uid::current().is_root()),
+            Some(host) => host.is_root(),
+        }
+    }
+
+    /// The first of [`AUTO_PROGRAMS`] the target has
+    fn auto_program(&self) -> io::Result<&'static str> {
+        if let Some(program) = self.auto_program.get() {
+            return Ok(program);
+        }
+        let program = AUTO_PROGRAMS
+            .into_iter()
+            .find(|program| {
+                self.command(&["sh", "-c", "command -v \"$1\"", "sh", program])
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .is_ok_and(|status| status.success())
+            })
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "None of {} found on the target; pick how to elevate with --elevate",
+                    AUTO_PROGRAMS.join(", ")
+                ),
+            ))?;
+        log::debug!("Elevating with {}", program);
+        Ok(self.auto_program.get_or_init(|| program))
+    }
+
+    /// The program escalating commands on the target, if any
+    fn escalation(&self) -> io::Result<Option<&'static str>> {
+        self.elevate
+            .program(|| self.is_root(), || self.auto_program())
+    }
+
+    /// `argv` to run as root on the target, escalated as configured.
+    ///
+    /// # Errors
+    ///
+    /// If it could not be determined whether the remote user is root, or `auto` found no way to
+    /// escalate
+    pub fn elevated_command(&self, argv: &[&str]) -> io::Result<Command> {
+        let escalate = self.escalation()?;
+        let argv = escalate
+            .into_iter()
+            .chain(argv.iter().copied())
+            .collect::<Vec<_>>();
+        Ok(self.command(&argv))
+    }
+
+    /// As with [`Target::elevated_command`], but with `env` as the entire environment.
+    ///
+    /// Escalation tools each have their own take on which variables make it through, so the
+    /// environment is reset with `env -i` once escalated. Running directly on this machine, the
+    /// environment is simply set on the process.
+    ///
+    /// # Errors
+    ///
+    /// If it could not be determined whether the remote user is root, or `auto` found no way to
+    /// escalate
+    pub fn sanitised_elevated_command(
+        &self,
+        env: &[(&str, &str)],
+        argv: &[&str],
+    ) -> io::Result<Command> {
+        let escalate = self.escalation()?;
+        if escalate.is_none() && self.remote.is_none() {
+            let mut cmd = self.command(argv);
+            cmd.env_clear().envs(env.iter().copied());
+            return Ok(cmd);
+        }
+
+        let assignments = env
+            .iter()
+            .map(|(key, val)| format!("{}={}", key, val))
+            .collect::<Vec<_>>();
+        let full_argv = escalate
+            .into_iter()
+            .chain(["env", "-i"])
+            .chain(assignments.iter().map(String::as_str))
+            .chain(argv.iter().copied())
+            .collect::<Vec<_>>();
+        Ok(self.command(&full_argv))
+    }
+
+    /// Copies the closure of a locally built path to the target. Nothing to do when local.
+    ///
+    /// # Errors
+    ///
+    /// If `nix copy` fails
+    pub fn copy_closure(&self, path: &Utf8Path, use_substitutes: bool) -> io::Result<()> {
+        match &self.remote {
+            None => Ok(()),
+            Some(host) => NixCopy {
+                to: Some(host),
+                use_substitutes,
+                ..NixCopy::default()
+            }
+            .run(path),
+        }
+    }
+
+    /// Resolves sym-links on the target, e.g. a profile to the store path of its generation.
+    ///
+    /// # Errors
+    ///
+    /// If the path could not be resolved
+    pub fn canonicalize(&self, path: &Utf8Path) -> io::Result<Utf8PathBuf> {
+        match &self.remote {
+            None => path.canonicalize_utf8(),
+            Some(_) => output(&mut self.command(&["readlink", "-f", path.as_str()]))
+                .map(|out| Utf8PathBuf::from(out.trim_end())),
+        }
+    }
+
+    /// Where a sym-link on the target points to, without resolving any further
+    ///
+    /// # Errors
+    ///
+    /// If the path could not be read as a link
+    pub fn read_link(&self, path: &Utf8Path) -> io::Result<Utf8PathBuf> {
+        match &self.remote {
+            None => path.read_link_utf8(),
+            Some(_) => output(&mut self.command(&["readlink", path.as_str()]))
+                .map(|out| Utf8PathBuf::from(out.trim_end())),
+        }
+    }
+
+    /// Names of the entries in a directory on the target. Missing directories are a `NotFound`.
+    ///
+    /// # Errors
+    ///
+    /// If the directory could not be read
+    pub fn read_dir_names(&self, dir: &Utf8Path) -> io::Result<Vec<String>> {
+        let mut names = match &self.remote {
+            None => dir
+                .read_dir_utf8()?
+                .map(|entry| entry.map(|e| e.file_name().to_string()))
+                .collect::<io::Result<Vec<_>>>()?,
+            Some(_) => {
+                // `test` exits with 1 for a missing dir, ssh with 255 when it can't connect
+                let mut test_dir = self.command(&["test", "-d", dir.as_str()]);
+                log::trace!("running: {:?}", test_dir);
+                let status = test_dir.status()?;
+                if status.code() == Some(1) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("No directory {} on the target", dir),
+                    ));
+                }
+                output(&mut self.command(&["ls", "-1A", dir.as_str()]))?
+                    .lines()
+                    .map(String::from)
+                    .collect()
+            }
+        };
+        names.sort();
+        Ok(names)
+    }
+}
+
+/// `nix copy` between stores. `None` is the local store.
+#[derive(Debug, Default)]
+pub struct NixCopy<'a> {
+    pub from: Option<&'a SshHost>,
+    pub to: Option<&'a SshHost>,
+    /// Copy the derivation, rather than its outputs
+    pub derivation: bool,
+    /// Let the destination fetch from its substituters
+    pub use_substitutes: bool,
+}
+
+impl NixCopy<'_> {
+    /// Copies the closure of `path`
+    ///
+    /// # Errors
+    ///
+    /// If `nix copy` fails
+    pub fn run(&self, path: &Utf8Path) -> io::Result<()> {
+        let store_name =
+            |host: Option<&SshHost>| host.map_or("local store", |h| h.host()).to_string();
+        log::info!(
+            "Copying {} from {} to {}",
+            path,
+            store_name(self.from),
+            store_name(self.to)
+        );
+        run(&mut self.command(path))
+    }
+
+    fn command(&self, path: &Utf8Path) -> Command {
+        let mut cmd = Command::new("nix");
+        cmd.arg("copy");
+        if let Some(from) = self.from {
+            cmd.args(["--from", &from.store_uri()]);
+        }
+        if let Some(to) = self.to {
+            cmd.args(["--to", &to.store_uri()]);
+        }
+        if self.derivation {
+            cmd.arg("--derivation");
+        }
+        if self.use_substitutes {
+            cmd.arg("--substitute-on-destination");
+        }
+        // Only one set of options makes it to ssh. The control path is keyed by host, so this
+        // still reuses the connection to the one, and shares a new one for the other.
+        if let Some(host) = self.from.or(self.to) {
+            cmd.env("NIX_SSHOPTS", host.nix_sshopts());
+        }
+        cmd.arg(path.as_str());
+        cmd
+    }
+}
+
+/// Quotes an argument for a posix shell, leaving it as-is when nothing needs escaping
+/// ```
+/// use nixos_rsbuild::remote::shell_quote;
+/// assert_eq!(shell_quote("/nix/store/abc-foo"), "/nix/store/abc-foo");
+/// assert_eq!(shell_quote("it's"), r"'it'\''s'");
+/// assert_eq!(shell_quote(""), "''");
+/// ```
+pub fn shell_quote(arg: &str) -> String {
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:@%+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_plain) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Remote targets without a remote: ssh is swapped for a shim, which runs commands locally
+#[cfg(test)]
+pub(crate) mod test_support {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    /// A stand-in for `ssh` that logs its args to `<dir>/log`, and runs the remote command
+    /// locally
+    pub(crate) fn ssh_shim(dir: &Utf8Path) -> Utf8PathBuf {
+        let shim = dir.join("ssh");
+        let script = format!(
+            r#"#!/bin/sh
+echo "$*" >> {log}
+while [ "$#" -gt 0 ] && [ "$1" != "--" ]; do shift; done
+[ "$#" -gt 0 ] || exit 0
+shift
+exec sh -c "$*"
+"#,
+            log = dir.join("log")
+        );
+        std::fs::write(&shim, script).unwrap();
+        std::fs::set_permissions(&shim, std::fs::Permissions::from_mode(0o755)).unwrap();
+        shim
+    }
+
+    /// `deploy@lab`, reached through [`ssh_shim`]
+    pub(crate) fn shim_target(dir: &Utf8Path, elevate: Elevate) -> Target {
+        Target {
+            remote: Some(SshHost::with_ssh(ssh_shim(dir), "deploy@lab").unwrap()),
+            elevate,
+            auto_program: OnceCell::new(),
+        }
+    }
+
+    /// The remote commands run so far, as logged by [`ssh_shim`]
+    pub(crate) fn remote_commands(dir: &Utf8Path) -> Vec<String> {
+        std::fs::read_to_string(dir.join("log"))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.split_once(" -- ").map(|(_, cmd)| cmd.to_string()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::{test_support::ssh_shim, *};
+
+    #[test]
+    fn remote_commands_share_connection() {
+        let td = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(td.path()).unwrap();
+        let host = SshHost::with_ssh(ssh_shim(dir), "deploy@lab").unwrap();
+        let control_path = format!("ControlPath={}/ssh-%n", host._control_dir.path().display());
+        let target = Target {
+            remote: Some(host),
+            elevate: Elevate::Sudo,
+            auto_program: OnceCell::new(),
+        };
+
+        let echoed = output(&mut target.command(&["echo", "it's a", "$HOME"])).unwrap();
+        assert_eq!(echoed, "it's a $HOME\n");
+
+        let names = target.read_dir_names(dir).unwrap();
+        assert_eq!(names, ["log", "ssh"]);
+        let missing = target.read_dir_names(&dir.join("nope")).unwrap_err();
+        assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+        // Failing to reach the host is not a missing dir
+        let unreachable = dir.join("unreachable");
+        std::fs::write(&unreachable, "#!/bin/sh\nexit 255\n").unwrap();
+        std::fs::set_permissions(&unreachable, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let offline = Target {
+            remote: Some(SshHost::with_ssh(&unreachable, "deploy@lab").unwrap()),
+            elevate: Elevate::None,
+            auto_program: OnceCell::new(),
+        };
+        let failed = offline.read_dir_names(dir).unwrap_err();
+        assert_ne!(failed.kind(), io::ErrorKind::NotFound);
+
+        let is_root = nix::unistd::Uid::current().is_root();
+        let elevated = target.elevated_command(&["true"]).unwrap();
+        let remote_cmd = elevated.get_args().last().unwrap().to_owned();
+        assert_eq!(remote_cmd, if is_root { "true" } else { "sudo true" });
+
+        // Whoever runs the tests, the remote user is not root from here on
+        let mut target = Target {
+            elevate: Elevate::Doas,
+            ..target
+        };
+        target.remote.as_mut().unwrap().is_root = OnceCell::from(false);
+        let sanitised = target
+            .sanitised_elevated_command(&[("LOCALE_ARCHIVE", "/run/locale")], &["true"])
+            .unwrap();
+        let remote_cmd = sanitised.get_args().last().unwrap().to_owned();
+        assert_eq!(remote_cmd, "doas env -i LOCALE_ARCHIVE=/run/locale true");
+
+        drop(target);
+        let log = std::fs::read_to_string(dir.join("log")).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        // echo, test and ls, test, id, and closing the connection
+        assert_eq!(lines.len(), 6, "{}", log);
+        assert!(lines.iter().all(|l| l.contains(&control_path)), "{}", log);
+        assert!(lines[4].ends_with("deploy@lab -- id -u"), "{}", log);
+        assert!(lines[5].ends_with("-O exit deploy@lab"), "{}", log);
+    }
+
+    #[test]
+    fn no_elevation_as_root() {
+        let td = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(td.path()).unwrap();
+        for elevate in [Elevate::Auto, Elevate::Sudo, Elevate::Doas, Elevate::Run0] {
+            let mut target = test_support::shim_target(dir, elevate);
+            target.remote.as_mut().unwrap().is_root = OnceCell::from(true);
+            let elevated = target.elevated_command(&["true"]).unwrap();
+            assert_eq!(elevated.get_args().last().unwrap(), "true");
+            let sanitised = target
+                .sanitised_elevated_command(&[("LOCALE_ARCHIVE", "/run/locale")], &["true"])
+                .unwrap();
+            let remote_cmd = sanitised.get_args().last().unwrap().to_owned();
+            assert_eq!(remote_cmd, "env -i LOCALE_ARCHIVE=/run/locale true");
+        }
+        // Nothing was looked for
+        assert!(test_support::remote_commands(dir).is_empty());
+    }
+
+    #[test]
+    fn auto_elevation_looks_for_programs() {
+        let td = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(td.path()).unwrap();
+        let mut target = test_support::shim_target(dir, Elevate::Auto);
+        target.remote.as_mut().unwrap().is_root = OnceCell::from(false);
+
+        // The shim runs the probes here, so which is found depends on this machine
+        match target.elevated_command(&["true"]) {
+            Ok(elevated) => {
+                let remote_cmd = elevated.get_args().last().unwrap().to_owned();
+                let program = remote_cmd.to_str().unwrap().strip_suffix(" true").unwrap();
+                assert!(AUTO_PROGRAMS.contains(&program), "{}", program);
+                // and once found, it is not looked for again
+                let probes = test_support::remote_commands(dir).len();
+                target.elevated_command(&["true"]).unwrap();
+                assert_eq!(test_support::remote_commands(dir).len(), probes);
+            }
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+        }
+        assert_eq!(
+            test_support::remote_commands(dir)[0],
+            r#"sh -c 'command -v "$1"' sh sudo"#
+        );
+    }
+
+    #[test]
+    fn nix_copy_args() {
+        let builder = SshHost::with_ssh("ssh", "builder").unwrap();
+        let target = SshHost::with_ssh("ssh", "root@lab").unwrap();
+        let path = Utf8Path::new("/nix/store/abc-nixos-system-lab");
+        let args = |copy: NixCopy| {
+            copy.command(path)
+                .get_args()
+                .map(|a| a.to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        assert_eq!(
+            args(NixCopy {
+                to: Some(&builder),
+                derivation: true,
+                ..NixCopy::default()
+            }),
+            "copy --to ssh://builder --derivation /nix/store/abc-nixos-system-lab"
+        );
+        assert_eq!(
+            args(NixCopy {
+                from: Some(&builder),
+                to: Some(&target),
+                use_substitutes: true,
+                ..NixCopy::default()
+            }),
+            "copy --from ssh://builder --to ssh://root@lab --substitute-on-destination /nix/store/abc-nixos-system-lab"
+        );
+    }
+}
@@ -0,0 +1,69 @@
+//! `nix repl` with a host's configuration in scope: `config`, `options`, `pkgs` and `lib` are
+//! bound, as they would be inside a NixOS module.
+use std::{io, process::Command};
+
+use camino::Utf8PathBuf;
+
+use crate::utils;
+
+/// The expression `nix repl` starts out with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostRepl {
+    /// Evaluates to the host's configuration, i.e. the result of `nixosSystem` or
+    /// `import <nixpkgs/nixos> {}`
+    pub host: String,
+    /// Set as `NIXOS_CONFIG` for the repl
+    pub nixos_config: Option<Utf8PathBuf>,
+}
+
+impl HostRepl {
+    /// The attribute set loaded into scope
+    fn expr(&self) -> String {
+        format!(
+            "let host = {}; in {{ inherit (host) config options pkgs; inherit (host.pkgs) lib; }}",
+            self.host
+        )
+    }
+
+    /// Starts `nix repl`, returning once it is quit
+    pub fn run(&self, extra_flags: &[String]) -> io::Result<()> {
+        log::info!("Starting repl for {}", self.host);
+
+        let mut cmd = Command::new("nix");
+        cmd.args(["repl", "--expr", &self.expr()]).args(extra_flags);
+        if let Some(cfg) = &self.nixos_config {
+            cmd.env("NIXOS_CONFIG", cfg);
+        }
+        utils::run(&mut cmd)
+    }
+}
+
+/// `."nixosConfigurations"."lab"`: each component quoted, as hostnames need not be valid nix
+/// identifiers
+pub fn attr_selector<S: AsRef<str>>(attr_path: &[S]) -> String {
+    attr_path
+        .iter()
+        .map(|part| format!(".\"{}\"", part.as_ref()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_expr() {
+        let repl = HostRepl {
+            host: format!(
+                "(builtins.getFlake \"/etc/nixos\"){}",
+                attr_selector(&["nixosConfigurations", "0-lab"])
+            ),
+            nixos_config: None,
+        };
+        assert_eq!(
+            repl.expr(),
+            r#"let host = (builtins.getFlake "/etc/nixos")."nixosConfigurations"."0-lab"; in { inherit (host) config options pkgs; inherit (host.pkgs) lib; }"#
+        );
+        assert_eq!(attr_selector::<&str>(&[]), "");
+    }
+}
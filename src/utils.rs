@@ -1,11 +1,29 @@
 use std::{
     fs::File,
     io::{self, BufRead},
-    path::Path,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
 };
 
 pub const DEFAULT_FILE_DIR: &str = "/etc/nixos";
 pub const DEFAULT_FLAKE_NIX: &str = "/etc/nixos/flake.nix";
+pub const DEFAULT_CONFIGURATION_NIX: &str = "/etc/nixos/configuration.nix";
+/// Where result links go when running as root. Unlike `/tmp`, it isn't world-writable.
+pub const ROOT_RES_DIR: &str = "/run/nixos-rsbuild";
+
+/// Directory result links are kept in, when no `--res-dir` is given: the temp-dir, or
+/// [`ROOT_RES_DIR`] as root.
+///
+/// # Errors
+///
+/// If [`ROOT_RES_DIR`] could not be created
+pub fn res_dir_base() -> io::Result<PathBuf> {
+    if !nix::unistd::Uid::current().is_root() {
+        return Ok(std::env::temp_dir());
+    }
+    std::fs::create_dir_all(ROOT_RES_DIR)?;
+    Ok(PathBuf::from(ROOT_RES_DIR))
+}
 
 /// Reads the first line of a file. Useful for files such as `/proc/sys/kernel/hostname`
 ///
@@ -18,3 +36,66 @@ pub fn read_fst_line(file_path: &Path) -> io::Result<String> {
     reader.read_line(&mut line_buf)?;
     Ok(line_buf)
 }
+
+/// Runs the command to completion, treating a non-zero exit as an error
+///
+/// # Errors
+///
+/// If the command could not be spawned, or did not succeed
+pub fn run(cmd: &mut Command) -> io::Result<()> {
+    log::trace!("running: {:?}", cmd);
+    let status = cmd.status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{:?} failed: {}",
+            cmd.get_program(),
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// As with [`run`], capturing stdout
+///
+/// # Errors
+///
+/// If the command could not be spawned, did not succeed, or wrote invalid utf8
+pub fn output(cmd: &mut Command) -> io::Result<String> {
+    log::trace!("running: {:?}", cmd);
+    let out = cmd.stderr(Stdio::inherit()).output()?;
+    if !out.status.success() {
+        return Err(io::Error::other(format!(
+            "{:?} failed: {}",
+            cmd.get_program(),
+            out.status
+        )));
+    }
+    String::from_utf8(out.stdout).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// As with [`run`], capturing stderr, for commands that report there. It is passed on in the
+/// error, should the command fail.
+///
+/// # Errors
+///
+/// If the command could not be spawned, or did not succeed
+pub fn stderr_output(cmd: &mut Command) -> io::Result<String> {
+    log::trace!("running: {:?}", cmd);
+    let out = cmd.stdout(Stdio::null()).output()?;
+    let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+    if !out.status.success() {
+        return Err(io::Error::other(format!(
+            "{:?} failed: {}\n{}",
+            cmd.get_program(),
+            out.status,
+            stderr.trim_end()
+        )));
+    }
+    Ok(stderr)
+}
+
+/// Bytes, in MiB to one decimal: `4.2 MiB`
+#[allow(clippy::cast_precision_loss, reason = "only displayed to one decimal")]
+pub fn human_bytes(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
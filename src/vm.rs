@@ -0,0 +1,251 @@
+//! Launching the VM that `build-vm` produces: `result/bin/run-<host>-vm`. The run script is
+//! configured through the environment: `QEMU_OPTS`, `QEMU_NET_OPTS`, `QEMU_KERNEL_PARAMS` and
+//! `NIX_DISK_IMAGE`.
+use std::{fmt::Display, io, process::Command, str::FromStr};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Args;
+
+use crate::utils;
+
+/// `[tcp:|udp:]<host-port>:<guest-port>`: a port on this machine forwarded into the VM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortForward {
+    pub udp: bool,
+    pub host: u16,
+    pub guest: u16,
+}
+
+impl FromStr for PortForward {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let (udp, ports) = match val.split_once(':') {
+            Some(("udp", ports)) => (true, ports),
+            Some(("tcp", ports)) => (false, ports),
+            _ => (false, val),
+        };
+        let parse = |port: &str| {
+            port.parse::<u16>()
+                .map_err(|e| format!("invalid port `{}` in `{}`: {}", port, val, e))
+        };
+        let (host, guest) = ports.split_once(':').ok_or_else(|| {
+            format!(
+                "expected [tcp:|udp:]<host-port>:<guest-port>, got `{}`",
+                val
+            )
+        })?;
+        Ok(Self {
+            udp,
+            host: parse(host)?,
+            guest: parse(guest)?,
+        })
+    }
+}
+
+/// As `QEMU_NET_OPTS` wants it: `hostfwd=tcp::2222-:22`
+impl Display for PortForward {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let proto = if self.udp { "udp" } else { "tcp" };
+        write!(f, "hostfwd={}::{}-:{}", proto, self.host, self.guest)
+    }
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct RunVmArgs {
+    #[clap(long, value_name = "MiB")]
+    /// Memory of the VM. Defaults to `virtualisation.memorySize` of the configuration.
+    pub memory: Option<u32>,
+    #[clap(long)]
+    /// CPU cores of the VM. Defaults to `virtualisation.cores` of the configuration.
+    pub cores: Option<u32>,
+    #[clap(long, value_name = "PATH")]
+    /// Where the VM's disk image lives. Defaults to `./<host>.qcow2`.
+    pub disk_image: Option<Utf8PathBuf>,
+    #[clap(long)]
+    /// Keep a disk image this run created, rather than removing it once the VM shuts down.
+    ///
+    /// Disk images that existed beforehand are always kept.
+    pub keep_disk: bool,
+    #[clap(long)]
+    /// No graphical window: the VM's serial console is attached to the terminal instead
+    pub headless: bool,
+    #[clap(long = "forward", value_name = "[tcp:|udp:]HOST:GUEST")]
+    /// Forward a port on this machine into the VM, e.g. `2222:22`. Can be repeated.
+    pub forwards: Vec<PortForward>,
+}
+
+impl RunVmArgs {
+    /// The environment for the run script, added to any `QEMU_OPTS`/`QEMU_NET_OPTS`/
+    /// `QEMU_KERNEL_PARAMS` already set, as looked up with `current_var`.
+    fn env(
+        &self,
+        disk_image: &Utf8Path,
+        current_var: impl Fn(&str) -> Option<String>,
+    ) -> Vec<(&'static str, String)> {
+        let mut qemu_opts = Vec::new();
+        if let Some(memory) = self.memory {
+            qemu_opts.push(format!("-m {}", memory));
+        }
+        if let Some(cores) = self.cores {
+            qemu_opts.push(format!("-smp {}", cores));
+        }
+        let mut kernel_params = Vec::new();
+        if self.headless {
+            qemu_opts.push("-nographic".to_string());
+            kernel_params.push("console=ttyS0".to_string());
+        }
+        let net_opts = self
+            .forwards
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+
+        let extend = |var: &str, sep: &str, ours: Vec<String>| {
+            current_var(var)
+                .filter(|theirs| !theirs.is_empty())
+                .into_iter()
+                .chain(ours)
+                .collect::<Vec<_>>()
+                .join(sep)
+        };
+        [
+            ("QEMU_OPTS", extend("QEMU_OPTS", " ", qemu_opts)),
+            ("QEMU_NET_OPTS", extend("QEMU_NET_OPTS", ",", net_opts)),
+            (
+                "QEMU_KERNEL_PARAMS",
+                extend("QEMU_KERNEL_PARAMS", " ", kernel_params),
+            ),
+            ("NIX_DISK_IMAGE", disk_image.to_string()),
+        ]
+        .into_iter()
+        .filter(|(_, val)| !val.is_empty())
+        .collect()
+    }
+}
+
+/// `<result>/bin/run-<host>-vm`, and `<host>`
+fn run_script(result: &Utf8Path) -> io::Result<(Utf8PathBuf, String)> {
+    let bin = result.join("bin");
+    for entry in bin.read_dir_utf8()? {
+        let entry = entry?;
+        if let Some(host) = entry
+            .file_name()
+            .strip_prefix("run-")
+            .and_then(|name| name.strip_suffix("-vm"))
+        {
+            return Ok((entry.path().to_path_buf(), host.to_string()));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("No run-<host>-vm script in {}", bin),
+    ))
+}
+
+/// Launches the VM built to `<res_dir>/result`, waiting for it to shut down.
+///
+/// # Errors
+///
+/// - no run script in the build result
+/// - the VM could not be started, or exited unsuccessfully
+pub fn run_vm(res_dir: &Utf8Path, args: &RunVmArgs) -> io::Result<()> {
+    let (script, host) = run_script(&res_dir.join("result"))?;
+    let disk_image = args
+        .disk_image
+        .clone()
+        .unwrap_or_else(|| Utf8PathBuf::from(format!("{}.qcow2", host)));
+    let fresh_disk = !disk_image.exists();
+
+    log::info!("Starting VM {} with disk image {}", host, disk_image);
+    let mut cmd = Command::new(&script);
+    cmd.envs(args.env(&disk_image, |var| std::env::var(var).ok()));
+    let res = utils::run(&mut cmd);
+
+    if fresh_disk && !args.keep_disk && disk_image.exists() {
+        log::info!("Removing disk image {}", disk_image);
+        std::fs::remove_file(&disk_image)?;
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_forward() {
+        let fwd = "2222:22".parse::<PortForward>().unwrap();
+        assert_eq!(fwd.to_string(), "hostfwd=tcp::2222-:22");
+        let fwd = "udp:5353:53".parse::<PortForward>().unwrap();
+        assert_eq!(fwd.to_string(), "hostfwd=udp::5353-:53");
+        assert!("2222".parse::<PortForward>().is_err());
+        assert!("sctp:1:2".parse::<PortForward>().is_err());
+        assert!("80:http".parse::<PortForward>().is_err());
+    }
+
+    #[test]
+    fn run_script_env() {
+        let args = RunVmArgs {
+            memory: Some(4096),
+            cores: Some(4),
+            headless: true,
+            forwards: vec!["2222:22".parse().unwrap(), "8080:80".parse().unwrap()],
+            ..RunVmArgs::default()
+        };
+        let disk_image = Utf8Path::new("/srv/vms/lab.qcow2");
+        let env = args.env(disk_image, |_| None);
+        let get = |env: &[(&str, String)], var: &str| {
+            env.iter()
+                .find(|(key, _)| *key == var)
+                .map(|(_, val)| val.clone())
+        };
+        assert_eq!(
+            get(&env, "QEMU_OPTS").as_deref(),
+            Some("-m 4096 -smp 4 -nographic")
+        );
+        assert_eq!(
+            get(&env, "QEMU_NET_OPTS").as_deref(),
+            Some("hostfwd=tcp::2222-:22,hostfwd=tcp::8080-:80")
+        );
+        assert_eq!(
+            get(&env, "QEMU_KERNEL_PARAMS").as_deref(),
+            Some("console=ttyS0")
+        );
+
+        // Added to what is already set
+        let env = args.env(disk_image, |var| match var {
+            "QEMU_OPTS" => Some("-vga virtio".to_string()),
+            "QEMU_NET_OPTS" => Some("hostfwd=tcp::8443-:443".to_string()),
+            "QEMU_KERNEL_PARAMS" => Some(String::new()),
+            _ => None,
+        });
+        assert_eq!(
+            get(&env, "QEMU_OPTS").as_deref(),
+            Some("-vga virtio -m 4096 -smp 4 -nographic")
+        );
+        assert_eq!(
+            get(&env, "QEMU_NET_OPTS").as_deref(),
+            Some("hostfwd=tcp::8443-:443,hostfwd=tcp::2222-:22,hostfwd=tcp::8080-:80")
+        );
+        assert_eq!(
+            get(&env, "QEMU_KERNEL_PARAMS").as_deref(),
+            Some("console=ttyS0")
+        );
+        assert_eq!(
+            get(&env, "NIX_DISK_IMAGE").as_deref(),
+            Some("/srv/vms/lab.qcow2")
+        );
+        let env = RunVmArgs::default().env(disk_image, |_| None);
+        assert_eq!(env, [("NIX_DISK_IMAGE", disk_image.to_string())]);
+
+        let td = tempfile::tempdir().unwrap();
+        let result = Utf8Path::from_path(td.path()).unwrap();
+        assert!(run_script(result).is_err());
+        std::fs::create_dir(result.join("bin")).unwrap();
+        std::fs::write(result.join("bin/run-lab-vm"), "").unwrap();
+        let (script, host) = run_script(result).unwrap();
+        assert_eq!(script, result.join("bin/run-lab-vm"));
+        assert_eq!(host, "lab");
+    }
+}